@@ -0,0 +1,32 @@
+extern crate metrolti_lib;
+
+use std::path::Path;
+use std::time::Instant;
+
+use metrolti_lib::maps;
+use metrolti_lib::metro_game::StateUpdate;
+use metrolti_lib::protocol::{ self, Encoding };
+use metrolti_lib::sim::{ self, SimSettings };
+
+const ITERATIONS: u32 = 1000;
+
+// Compares building a late-game state update in each encoding. Run with
+// `cargo bench`.
+pub fn main() {
+    let settings = SimSettings::load(Path::new("sims/bots.json")).expect("bench settings");
+    let result = sim::run(&settings, maps::builtin_maps());
+    println!("Model after {} ticks: {} stations, {} lines, {} trains",
+        result.ticks, result.model.station_count(), result.model.lines().len(), result.model.trains().len());
+    let update = StateUpdate::GameState(result.model);
+
+    for encoding in [Encoding::Json, Encoding::MessagePack].iter() {
+        let start = Instant::now();
+        let mut size = 0;
+        for _ in 0..ITERATIONS {
+            size = protocol::encode(&update, *encoding).len();
+        }
+        let elapsed = start.elapsed();
+        let micros = (elapsed.as_secs() as f64 * 1_000_000. + elapsed.subsec_nanos() as f64 / 1_000.) / ITERATIONS as f64;
+        println!("{:?}: {} bytes, {:.1}us per update", encoding, size, micros);
+    }
+}
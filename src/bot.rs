@@ -0,0 +1,184 @@
+use metro_game::{ square_distance, LineId, MetroModel, PlayerAction, StationId };
+use player_id::PlayerId;
+
+// An in-process player. Bots see the whole model each tick and answer with
+// the same actions a connected player could send.
+pub trait Bot {
+    fn name(&self) -> &'static str;
+    fn act(&mut self, me: &PlayerId, model: &MetroModel) -> Vec<PlayerAction>;
+}
+
+// Starts lines at stations nobody serves and grows its lines toward the
+// busiest stations it isn't already stopping at. Makes at most one move
+// every `think_interval` ticks so it plays at about human pace.
+pub struct GreedyBot {
+    pub think_interval: u64,
+    pub overcrowded: usize,
+}
+
+impl GreedyBot {
+    pub fn new() -> Self {
+        GreedyBot {
+            think_interval: 60,
+            overcrowded: 5,
+        }
+    }
+
+    // Unserved stations first, then overcrowded ones the bot doesn't stop
+    // at yet, busiest first.
+    fn targets(&self, me: &PlayerId, model: &MetroModel) -> Vec<StationId> {
+        let mut served = vec![false; model.station_count()];
+        let mut mine = vec![false; model.station_count()];
+        for line in model.lines().iter() {
+            let own = model.same_side(line.owner(), me);
+            for &StationId(i) in line.all_stations() {
+                if let Some(s) = served.get_mut(i) {
+                    *s = true;
+                }
+                if let Some(m) = mine.get_mut(i).filter(|_| own) {
+                    *m = true;
+                }
+            }
+        }
+        let mut targets: Vec<(bool, usize, usize)> = Vec::new();
+        for (i, s) in model.stations().iter().enumerate() {
+            if !served[i] {
+                targets.push((false, s.passenger_count(), i));
+            } else if !mine[i] && s.passenger_count() >= self.overcrowded {
+                targets.push((true, s.passenger_count(), i));
+            }
+        }
+        targets.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
+        targets.into_iter().map(|(_, _, i)| StationId(i)).collect()
+    }
+
+    fn new_line(&self, me: &PlayerId, model: &MetroModel, target: &StationId) -> Option<PlayerAction> {
+        if !model.lines().iter().any(|l| model.same_side(l.owner(), me) && l.is_unused()) {
+            return None;
+        }
+        let pos = model.get_station_pos(target)?;
+        let t = model.get_station(target)?.station_type();
+        // Prefer a partner of another type so passengers have somewhere to go
+        let partner = model.stations().iter().enumerate()
+            .filter(|&(i, _)| StationId(i) != *target)
+            .min_by(|&(_, a), &(_, b)| {
+                let key_a = (a.station_type() == t, square_distance(&pos, &a.position()));
+                let key_b = (b.station_type() == t, square_distance(&pos, &b.position()));
+                key_a.partial_cmp(&key_b).unwrap_or(::std::cmp::Ordering::Equal)
+            })
+            .map(|(i, _)| StationId(i))?;
+        Some(PlayerAction::NewLine(target.clone(), partner))
+    }
+
+    fn extend_line(&self, me: &PlayerId, model: &MetroModel, target: &StationId) -> Option<PlayerAction> {
+        let pos = model.get_station_pos(target)?;
+        let mut best: Option<(f32, PlayerAction)> = None;
+        for (i, line) in model.lines().iter().enumerate() {
+            if !model.same_side(line.owner(), me) || line.is_unused() || line.is_loop() {
+                continue;
+            }
+            let stations = line.all_stations();
+            if stations.contains(&target) {
+                continue;
+            }
+            let first = stations[0];
+            let last = stations[stations.len() - 1];
+            let ends = vec![
+                (first, PlayerAction::InsertAtLineBeginning(LineId(i), target.clone())),
+                (last, PlayerAction::InsertAtLineEnd(LineId(i), target.clone())),
+            ];
+            for (end, action) in ends {
+                let d = model.get_station_pos(end).map(|p| square_distance(&pos, &p)).unwrap_or(f32::MAX);
+                let closer = match best {
+                    Some((closest, _)) => d < closest,
+                    None => true,
+                };
+                if closer {
+                    best = Some((d, action));
+                }
+            }
+        }
+        best.map(|(_, action)| action)
+    }
+}
+
+impl Default for GreedyBot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bot for GreedyBot {
+    fn name(&self) -> &'static str {
+        "Greedy"
+    }
+
+    fn act(&mut self, me: &PlayerId, model: &MetroModel) -> Vec<PlayerAction> {
+        if self.think_interval > 0 && model.tick() % self.think_interval != 0 {
+            return Vec::new();
+        }
+        for target in self.targets(me, model) {
+            if let Some(action) = self.new_line(me, model, &target).or_else(|| self.extend_line(me, model, &target)) {
+                return vec![action];
+            }
+        }
+        Vec::new()
+    }
+}
+
+pub fn bot_kinds() -> Vec<&'static str> {
+    vec!["Greedy"]
+}
+
+pub fn bot_by_name(name: &str) -> Option<Box<dyn Bot + Send>> {
+    match name {
+        "Greedy" => Some(Box::new(GreedyBot::new())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metro_game::StationType;
+
+    fn model() -> MetroModel {
+        let mut model = MetroModel::new();
+        model.add_station(StationType::Circle, (0., 0.));
+        model.add_station(StationType::Circle, (100., 0.));
+        model.add_station(StationType::Square, (200., 0.));
+        model.add_station(StationType::Triangle, (-300., 0.));
+        model
+    }
+
+    #[test]
+    fn greedy_bot_connects_then_extends() {
+        let me = PlayerId::new(9);
+        let mut model = model();
+        let mut bot = GreedyBot::new();
+        model.add_line(PlayerId::new(1), (0., 0., 0.));
+        model.add_line(me, (0., 0., 0.));
+
+        match bot.act(&me, &model).as_slice() {
+            [PlayerAction::NewLine(StationId(0), StationId(2))] => {}
+            other => panic!("Expected a new line from 0 to 2, got {:?}", other),
+        }
+        model.start_new_line(&me, &StationId(0), &StationId(2));
+
+        match bot.act(&me, &model).as_slice() {
+            [PlayerAction::InsertAtLineBeginning(LineId(1), StationId(1))] => {}
+            other => panic!("Expected station 1 on the start of line 1, got {:?}", other),
+        }
+        model.insert_before_line(&LineId(1), &StationId(1));
+
+        match bot.act(&me, &model).as_slice() {
+            [PlayerAction::InsertAtLineBeginning(LineId(1), StationId(3))] => {}
+            other => panic!("Expected station 3 on the start of line 1, got {:?}", other),
+        }
+        model.insert_before_line(&LineId(1), &StationId(3));
+
+        assert!(bot.act(&me, &model).is_empty());
+        assert!(bot_by_name("Greedy").is_some());
+        assert!(bot_by_name("Clever").is_none());
+    }
+}
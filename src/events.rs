@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use player_id::*;
 use player::Player;
 use metro_game::PlayerAction;
@@ -7,5 +9,7 @@ pub enum InputEvent {
     PlayerAction(PlayerId, PlayerAction),
     Connection(PlayerId, Player),
     Disconnection(PlayerId),
+    // A player answered a ping after this long
+    RoundTrip(PlayerId, Duration),
 }
 
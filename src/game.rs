@@ -1,6 +1,8 @@
 use std::sync::mpsc::Receiver;
 
 use events::InputEvent;
+use metro_game::StateUpdate;
+use player_id::PlayerId;
 use ticks::Ticker;
 use randoms::Random;
 
@@ -9,3 +11,9 @@ pub trait Game<T: Ticker, R: Random> {
     fn main(&mut self);
 }
 
+
+// Runs a game one tick at a time on the caller's thread. The updates players
+// would have been sent that tick are handed back instead.
+pub trait SteppedGame {
+    fn step(&mut self, events: Vec<InputEvent>) -> Vec<(PlayerId, StateUpdate)>;
+}
@@ -0,0 +1,87 @@
+use std::time::{ Duration, Instant };
+
+// Tracks the pings sent to one player. Pings are numbered so a late pong
+// isn't mistaken for the answer to a newer one.
+#[derive(Debug)]
+pub struct Heartbeat {
+    max_missed: u32,
+    missed: u32,
+    next_ping: u32,
+    waiting_on: Option<(u32, Instant)>,
+    round_trip: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(max_missed: u32) -> Self {
+        Heartbeat {
+            max_missed: max_missed,
+            missed: 0,
+            next_ping: 0,
+            waiting_on: None,
+            round_trip: None,
+        }
+    }
+
+    // The payload for the next ping, or why the player should be dropped
+    pub fn ping(&mut self, now: Instant) -> Result<Vec<u8>, String> {
+        if self.waiting_on.is_some() {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return Err(format!("missed {} pongs", self.missed));
+            }
+        }
+        let n = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.waiting_on = Some((n, now));
+        Ok(n.to_string().into_bytes())
+    }
+
+    // Returns the round trip if this answers the latest ping
+    pub fn pong(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        match self.waiting_on {
+            Some((n, sent)) if n.to_string().as_bytes() == payload => {
+                self.waiting_on = None;
+                self.missed = 0;
+                self.round_trip = Some(now.duration_since(sent));
+                self.round_trip
+            }
+            _ => None,
+        }
+    }
+
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pongs_measure_round_trips() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(2);
+        let first = heartbeat.ping(start).unwrap();
+        assert_eq!(None, heartbeat.pong(b"7", start));
+        assert_eq!(Some(Duration::from_millis(40)), heartbeat.pong(&first, start + Duration::from_millis(40)));
+        // Answering twice doesn't count
+        assert_eq!(None, heartbeat.pong(&first, start + Duration::from_millis(90)));
+        assert_eq!(Some(Duration::from_millis(40)), heartbeat.round_trip());
+    }
+
+    #[test]
+    fn missed_pongs_drop_the_player() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(2);
+        let late = heartbeat.ping(start).unwrap();
+        let second = heartbeat.ping(start).unwrap();
+        // A pong for an older ping is too late to count
+        assert_eq!(None, heartbeat.pong(&late, start));
+        assert!(heartbeat.pong(&second, start).is_some());
+
+        heartbeat.ping(start).unwrap();
+        heartbeat.ping(start).unwrap();
+        assert_eq!(Err("missed 2 pongs".to_string()), heartbeat.ping(start));
+    }
+}
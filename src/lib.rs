@@ -7,19 +7,43 @@ extern crate tungstenite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+#[macro_use]
+extern crate schemars;
 extern crate rand;
+extern crate rmp_serde;
+#[macro_use]
+extern crate log;
 #[cfg(test)] 
 #[macro_use]
 extern crate pretty_assertions;
 
+pub mod bot;
 pub mod game;
 pub mod metro_game;
-mod events;
-mod player_id;
-mod player;
+pub mod maps;
+pub mod events;
+pub mod player_id;
+pub mod placement;
+pub mod player;
+pub mod protocol;
 pub mod server;
+pub mod sim;
 mod sexpect;
 pub mod web;
 pub mod ticks;
 pub mod randoms;
+pub mod scenario;
+pub mod scoring;
+pub mod visibility;
+pub mod out_queue;
+pub mod heartbeat;
+pub mod rate_limit;
+pub mod logging;
+pub mod metrics;
+pub mod rooms;
+pub mod proxy;
+pub mod results;
+pub mod ruleset;
+pub mod spawning;
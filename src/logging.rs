@@ -0,0 +1,237 @@
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io::{ self, Write };
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use log::{ self, LevelFilter, Log, Metadata, Record };
+use log::kv::{ self, Key, Value, VisitSource };
+use serde_json::{ self, Map };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // `time LEVEL target: message key=value ...`
+    Text,
+    // One JSON object per line, with the fields alongside the message
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogOutput {
+    Stderr,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub level: LevelFilter,
+    // Levels for modules and everything under them. The longest matching
+    // module wins.
+    pub modules: Vec<(String, LevelFilter)>,
+    pub format: LogFormat,
+    pub output: LogOutput,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+            format: LogFormat::Text,
+            output: LogOutput::Stderr,
+        }
+    }
+}
+
+impl LogSettings {
+    // Filters come from METROLTI_LOG, e.g. `warn,metrolti_lib::player=debug`.
+    // METROLTI_LOG_FORMAT can be `text` or `json`, and METROLTI_LOG_FILE
+    // appends to a file instead of writing to stderr.
+    pub fn from_env() -> Result<Self, String> {
+        let mut settings = LogSettings::default();
+        if let Ok(filters) = env::var("METROLTI_LOG") {
+            settings.parse_filters(&filters)?;
+        }
+        match env::var("METROLTI_LOG_FORMAT").as_ref().map(|f| f.as_str()) {
+            Ok("json") => settings.format = LogFormat::Json,
+            Ok("text") | Err(_) => {}
+            Ok(other) => return Err(format!("Unknown log format {}", other)),
+        }
+        if let Ok(path) = env::var("METROLTI_LOG_FILE") {
+            settings.output = LogOutput::File(PathBuf::from(path));
+        }
+        Ok(settings)
+    }
+
+    pub fn parse_filters(&mut self, spec: &str) -> Result<(), String> {
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut halves = part.splitn(2, '=');
+            let first = halves.next().unwrap_or_default();
+            match halves.next() {
+                Some(level) => {
+                    let level = level.parse().map_err(|_| format!("Unknown log level {}", level))?;
+                    self.modules.push((first.to_string(), level));
+                }
+                None => {
+                    self.level = first.parse().map_err(|_| format!("Unknown log level {}", first))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .filter(|&(module, _)| target == module || target.starts_with(&format!("{}::", module)))
+            .max_by_key(|&(module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.level, |a, b| a.max(b))
+    }
+}
+
+struct Logger {
+    settings: LogSettings,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.settings.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let millis = now.as_secs() * 1000 + now.subsec_millis() as u64;
+        let line = format_record(record, self.settings.format, millis);
+        if let Ok(mut out) = self.out.lock() {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut out) = self.out.lock() {
+            let _ = out.flush();
+        }
+    }
+}
+
+// Collects a record's fields in the order they were given
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let json = if let Some(n) = value.to_u64() {
+            json!(n)
+        } else if let Some(n) = value.to_i64() {
+            json!(n)
+        } else if let Some(n) = value.to_f64() {
+            json!(n)
+        } else if let Some(b) = value.to_bool() {
+            json!(b)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.push((key.to_string(), json));
+        Ok(())
+    }
+}
+
+fn format_record(record: &Record, format: LogFormat, millis: u64) -> String {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    match format {
+        LogFormat::Text => {
+            let mut line = format!(
+                "{}.{:03} {:5} {}: {}",
+                millis / 1000, millis % 1000, record.level(), record.target(), record.args());
+            for (key, value) in fields.0 {
+                let _ = match value {
+                    serde_json::Value::String(s) if !s.contains(' ') && !s.is_empty() => write!(line, " {}={}", key, s),
+                    other => write!(line, " {}={}", key, other),
+                };
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert("time".to_string(), json!(millis));
+            object.insert("level".to_string(), json!(record.level().to_string()));
+            object.insert("target".to_string(), json!(record.target()));
+            object.insert("message".to_string(), json!(record.args().to_string()));
+            for (key, value) in fields.0 {
+                object.insert(key, value);
+            }
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+// Installs the logger for the rest of the process. Fails if there's one
+// already.
+pub fn init(settings: LogSettings) -> Result<(), String> {
+    let out: Box<dyn Write + Send> = match settings.output {
+        LogOutput::Stderr => Box::new(io::stderr()),
+        LogOutput::File(ref path) => Box::new(OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Could not open log file {}: {}", path.display(), e))?),
+    };
+    let max_level = settings.max_level();
+    log::set_boxed_logger(Box::new(Logger { settings: settings, out: Mutex::new(out) }))
+        .map_err(|e| e.to_string())?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use log::kv::ToValue;
+
+    use super::*;
+    use player_id::PlayerId;
+
+    #[test]
+    fn module_filters() {
+        let mut settings = LogSettings::default();
+        settings.parse_filters("warn, metrolti_lib::player=debug,metrolti_lib::player::inner=error").unwrap();
+        assert_eq!(LevelFilter::Warn, settings.level);
+        assert_eq!(LevelFilter::Warn, settings.level_for("metrolti_lib::metro_game"));
+        assert_eq!(LevelFilter::Debug, settings.level_for("metrolti_lib::player"));
+        assert_eq!(LevelFilter::Warn, settings.level_for("metrolti_lib::player_id"));
+        assert_eq!(LevelFilter::Error, settings.level_for("metrolti_lib::player::inner"));
+        assert_eq!(LevelFilter::Debug, settings.max_level());
+        assert!(settings.parse_filters("loud").is_err());
+    }
+
+    fn format_disconnect(format: LogFormat) -> String {
+        let player = PlayerId::new(3);
+        let kvs: &[(&str, Value)] = &[
+            ("player", player.to_value()),
+            ("tick", Value::from(40u64)),
+            ("reason", Value::from("too slow")),
+        ];
+        format_record(&Record::builder()
+            .level(Level::Warn)
+            .target("metrolti_lib::metro_game")
+            .args(format_args!("Disconnecting {}", "now"))
+            .key_values(&kvs)
+            .build(), format, 1_234_005)
+    }
+
+    #[test]
+    fn text_and_json_lines() {
+        assert_eq!(
+            "1234.005 WARN  metrolti_lib::metro_game: Disconnecting now player=3 tick=40 reason=\"too slow\"",
+            format_disconnect(LogFormat::Text));
+        assert_eq!(
+            r#"{"level":"WARN","message":"Disconnecting now","player":3,"reason":"too slow","target":"metrolti_lib::metro_game","tick":40,"time":1234005}"#,
+            format_disconnect(LogFormat::Json));
+    }
+}
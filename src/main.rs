@@ -5,22 +5,38 @@ extern crate serde_json;
 
 extern crate tungstenite;
 extern crate url;
+#[macro_use]
+extern crate log;
 
 use metrolti_lib::server as server;
+use metrolti_lib::player::ConnectionSettings;
 use metrolti_lib::metro_game as game;
-use metrolti_lib::ticks::TPSTicker;
-use metrolti_lib::randoms::RealRandom;
+use metrolti_lib::protocol;
 use metrolti_lib::web as web;
+use metrolti_lib::logging::{ self, LogSettings };
+use metrolti_lib::game::Game;
+use metrolti_lib::maps;
+use metrolti_lib::results::ResultsStore;
 
+use std::path::Path;
 use std::thread;
 
 use url::Url;
 use std::time::{Duration};
 
 pub fn main() {
-    thread::spawn(|| web::startup_web_frontend("localhost:3005".to_string(), "localhost:3004".to_string(), "./www/static/".to_string()));
-    thread::spawn(|| demo_player("ws://localhost:3004"));
-    server::listen::<game::MetroGame<TPSTicker, RealRandom>>("localhost:3004".to_string(), String::new());
+    let log_settings = LogSettings::from_env().unwrap_or_else(|e| panic!("{}", e));
+    logging::init(log_settings).expect("logger");
+    // The frontend sits behind the game's port, which hands it everything
+    // that isn't a websocket
+    let web = web::startup_web_frontend("127.0.0.1:0".to_string(), "./www/static/".to_string(), ResultsStore::from_env());
+    thread::spawn(|| demo_player("ws://localhost:3005/ws"));
+    let maps = maps::available_maps(Path::new("./maps/"));
+    server::listen("localhost:3005".to_string(), String::new(), Some(web.socket), ConnectionSettings::default(), move |events, ticker, random| {
+        let mut game = game::MetroGame::new(events, ticker, random);
+        game.set_maps(maps);
+        game
+    });
 }
 
 #[derive(Debug)]
@@ -31,7 +47,7 @@ enum DemoAction {
 }
 
 fn demo_player(addr: &str) {
-    println!("Starting demo player");
+    info!("Starting demo player");
     let mut ws = {
         let uri = Url::parse(addr).unwrap();
         loop {
@@ -40,6 +56,12 @@ fn demo_player(addr: &str) {
             }
         }
     };
+    let hello = protocol::ClientHandshake::Hello {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        client_name: "demo player".to_string(),
+        encoding: protocol::Encoding::Json,
+    };
+    ws.write_message(tungstenite::Message::text(serde_json::to_string(&hello).expect("demo hello"))).expect("demo hello");
     let actions_list = vec![
         DemoAction::WaitMessage("{\"LobbyCount\":2}".to_string()),
         DemoAction::WaitTime(2),
@@ -72,7 +94,7 @@ fn demo_player(addr: &str) {
         }
         if progress {
             curr = actions.next();
-            println!("Moving on to {:?}", curr);
+            debug!(action:? = curr; "Demo player moving on");
         }
     }
 }
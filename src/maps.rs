@@ -0,0 +1,278 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use serde_json;
+
+use metro_game::{ DeliveryScoring, MetroModel, Point, StationType, Terrain };
+use placement::DensityMap;
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct StartingStation {
+    #[serde(rename = "type")]
+    pub t: StationType,
+    pub position: Point,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct SpawnWeight {
+    #[serde(rename = "type")]
+    pub t: StationType,
+    pub weight: f64,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct WeekSchedule {
+    pub ticks_per_week: u64,
+    pub lines_per_week: u8,
+    pub max_lines_per_player: u8,
+    pub map_growth: f32,
+    pub max_extent: f32,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct MapDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub starting_stations: Vec<StartingStation>,
+    pub initial_margin: f32,
+    #[serde(default)]
+    pub terrain: Vec<Terrain>,
+    pub spawn_weights: Vec<SpawnWeight>,
+    #[serde(default = "DensityMap::uniform")]
+    pub density: DensityMap,
+    pub schedule: WeekSchedule,
+    // Flat unless the map rewards quick journeys
+    #[serde(default)]
+    pub delivery_scoring: DeliveryScoring,
+}
+
+impl MapDefinition {
+    pub fn build_model(&self) -> MetroModel {
+        let mut model = MetroModel::new();
+        let extent = self.schedule.max_extent;
+        model.set_bounds((-extent, -extent), (extent, extent));
+        for t in self.terrain.iter() {
+            model.add_terrain(t.clone());
+        }
+        for s in self.starting_stations.iter() {
+            model.add_station(s.t.clone(), s.position);
+        }
+        model.fit_bounds_to_stations(self.initial_margin);
+        model.set_delivery_scoring(self.delivery_scoring.clone());
+        model
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if self.name.trim().is_empty() {
+            problems.push("name must not be empty".to_string());
+        }
+        if self.starting_stations.is_empty() {
+            problems.push("at least one starting station is required".to_string());
+        }
+        if self.initial_margin < 0. {
+            problems.push(format!("initial_margin must not be negative (got {})", self.initial_margin));
+        }
+        if self.schedule.ticks_per_week == 0 {
+            problems.push("schedule.ticks_per_week must be greater than 0".to_string());
+        }
+        if self.schedule.max_lines_per_player == 0 {
+            problems.push("schedule.max_lines_per_player must be at least 1".to_string());
+        }
+        if self.schedule.map_growth < 0. {
+            problems.push(format!("schedule.map_growth must not be negative (got {})", self.schedule.map_growth));
+        }
+        if self.schedule.max_extent <= 0. {
+            problems.push(format!("schedule.max_extent must be positive (got {})", self.schedule.max_extent));
+        }
+        if let DeliveryScoring::Speed { target_ticks: 0, .. } = self.delivery_scoring {
+            problems.push("delivery_scoring.Speed.target_ticks must be greater than 0".to_string());
+        }
+        if self.spawn_weights.iter().any(|w| w.weight < 0.) {
+            problems.push("spawn_weights must not be negative".to_string());
+        }
+        if self.spawn_weights.iter().map(|w| w.weight).sum::<f64>() <= 0. {
+            problems.push("spawn_weights must add up to more than 0".to_string());
+        }
+        for (i, t) in self.terrain.iter().enumerate() {
+            let size = match *t {
+                Terrain::Lake { radius, .. } => radius,
+                Terrain::River { width, .. } => width,
+            };
+            if size <= 0. {
+                problems.push(format!("terrain[{}] must have a positive size", i));
+            }
+        }
+        if self.density.max_weight() <= 0. {
+            problems.push("density must be positive somewhere on the map".to_string());
+        }
+
+        // Place the stations one by one so overlaps are caught the same way
+        // the game would reject them.
+        let mut model = MetroModel::new();
+        let extent = self.schedule.max_extent;
+        model.set_bounds((-extent, -extent), (extent, extent));
+        for t in self.terrain.iter() {
+            model.add_terrain(t.clone());
+        }
+        for (i, s) in self.starting_stations.iter().enumerate() {
+            if !model.is_valid_station_pos(&s.position) {
+                problems.push(format!(
+                    "starting_stations[{}] at {:?} is outside the map, on terrain or too close to another station",
+                    i, s.position));
+            }
+            model.add_station(s.t.clone(), s.position);
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(PathBuf, io::Error),
+    Parse(String, serde_json::Error),
+    Invalid(String, Vec<String>),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::Io(ref path, ref e) =>
+                write!(f, "Could not read map {}: {}", path.display(), e),
+            MapError::Parse(ref source, ref e) =>
+                write!(f, "Could not parse map {}: {}", source, e),
+            MapError::Invalid(ref source, ref problems) => {
+                write!(f, "Map {} is invalid:", source)?;
+                for p in problems.iter() {
+                    write!(f, "\n  - {}", p)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn parse_map(source: &str, json: &str) -> Result<MapDefinition, MapError> {
+    let map: MapDefinition = serde_json::from_str(json)
+        .map_err(|e| MapError::Parse(source.to_string(), e))?;
+    map.validate()
+        .map_err(|problems| MapError::Invalid(source.to_string(), problems))?;
+    Ok(map)
+}
+
+pub fn load_map_file(path: &Path) -> Result<MapDefinition, MapError> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| MapError::Io(path.to_path_buf(), e))?;
+    parse_map(&path.display().to_string(), &json)
+}
+
+// Loads every .json file in the directory, skipping (and returning) the ones
+// that fail so one broken map doesn't take the rest down with it.
+pub fn load_maps_dir(dir: &Path) -> (Vec<MapDefinition>, Vec<MapError>) {
+    let mut maps = Vec::new();
+    let mut errors = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(MapError::Io(dir.to_path_buf(), e));
+            return (maps, errors);
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension() == Some(OsStr::new("json")))
+        .collect();
+    paths.sort();
+    for path in paths {
+        match load_map_file(&path) {
+            Ok(map) => maps.push(map),
+            Err(e) => errors.push(e),
+        }
+    }
+    (maps, errors)
+}
+
+const BUILTIN_MAPS: &[(&str, &str)] = &[
+    ("classic.json", include_str!("../maps/classic.json")),
+    ("riverside.json", include_str!("../maps/riverside.json")),
+    ("lakeland.json", include_str!("../maps/lakeland.json")),
+];
+
+pub fn builtin_maps() -> Vec<MapDefinition> {
+    BUILTIN_MAPS.iter()
+        .map(|&(source, json)| parse_map(source, json).unwrap_or_else(|e| panic!("{}", e)))
+        .collect()
+}
+
+// Built-in maps followed by any found in `dir`. A map in the directory with
+// the same name as an earlier one replaces it.
+pub fn available_maps(dir: &Path) -> Vec<MapDefinition> {
+    let mut maps = builtin_maps();
+    if dir.is_dir() {
+        let (loaded, errors) = load_maps_dir(dir);
+        for e in errors {
+            warn!(error:% = e; "Skipping map");
+        }
+        for map in loaded {
+            match maps.iter().position(|m| m.name == map.name) {
+                Some(i) => maps[i] = map,
+                None => maps.push(map),
+            }
+        }
+    }
+    maps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_maps_are_valid() {
+        let maps = builtin_maps();
+        assert_eq!(vec!["Classic", "Riverside", "Lakeland"], maps.iter().map(|m| m.name.as_str()).collect::<Vec<_>>());
+        let classic = maps[0].build_model();
+        assert_eq!(3, classic.station_count());
+        assert_eq!(((-145., -130.), (400., 170.)), classic.bounds());
+        assert_eq!(&DeliveryScoring::Flat, classic.delivery_scoring());
+        assert_eq!(&DeliveryScoring::Speed { target_ticks: 1800, max_bonus: 2 }, maps[2].build_model().delivery_scoring());
+    }
+
+    #[test]
+    fn invalid_maps_report_every_problem() {
+        let json = r#"{
+            "name": "Broken",
+            "starting_stations": [
+                { "type": "Circle", "position": [0, 0] },
+                { "type": "Square", "position": [10, 10] },
+                { "type": "Triangle", "position": [900, 0] }
+            ],
+            "initial_margin": 100,
+            "spawn_weights": [],
+            "schedule": { "ticks_per_week": 0, "lines_per_week": 1, "max_lines_per_player": 7, "map_growth": 75, "max_extent": 500 }
+        }"#;
+        match parse_map("broken.json", json) {
+            Err(MapError::Invalid(source, problems)) => {
+                assert_eq!("broken.json", source);
+                assert_eq!(vec![
+                    "schedule.ticks_per_week must be greater than 0".to_string(),
+                    "spawn_weights must add up to more than 0".to_string(),
+                    "starting_stations[1] at (10.0, 10.0) is outside the map, on terrain or too close to another station".to_string(),
+                    "starting_stations[2] at (900.0, 0.0) is outside the map, on terrain or too close to another station".to_string(),
+                ], problems);
+            }
+            other => panic!("Expected the map to be invalid: {:?}", other),
+        }
+
+        match parse_map("typo.json", r#"{ "name": "Typo", "starting_stations": [ { "type": "Hexagon", "position": [0, 0] } ] }"#) {
+            Err(MapError::Parse(ref source, _)) => assert_eq!("typo.json", source),
+            other => panic!("Expected a parse error: {:?}", other),
+        }
+    }
+}
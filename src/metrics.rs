@@ -0,0 +1,144 @@
+use std::fmt::Write;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+
+use metro_game::MetroModel;
+use rooms::ROOMS;
+
+// Counters and gauges for the whole server, read by the web frontend's
+// /metrics endpoint. Everything is a plain atomic so the game loop and
+// player threads can update them without waiting on each other.
+#[derive(Debug)]
+pub struct Metrics {
+    ticks: AtomicU64,
+    tick_micros: AtomicU64,
+    tick_overruns: AtomicU64,
+    connected_players: AtomicU64,
+    bots: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    state_bytes: AtomicU64,
+    delivered: AtomicU64,
+    max_blow_time: AtomicU64,
+    stations: AtomicU64,
+    week: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            ticks: AtomicU64::new(0),
+            tick_micros: AtomicU64::new(0),
+            tick_overruns: AtomicU64::new(0),
+            connected_players: AtomicU64::new(0),
+            bots: AtomicU64::new(0),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            state_bytes: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            max_blow_time: AtomicU64::new(0),
+            stations: AtomicU64::new(0),
+            week: AtomicU64::new(0),
+        }
+    }
+
+    // How long a tick's work took, and whether it ran past the tick length
+    pub fn record_tick(&self, work: Duration, overran: bool) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.tick_micros.fetch_add(micros(work), Ordering::Relaxed);
+        if overran {
+            self.tick_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn message_in(&self) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_out(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_state_size(&self, bytes: usize) {
+        self.state_bytes.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_game(&self, players: usize, bots: usize, model: &MetroModel) {
+        let delivered: u32 = model.delivery_stats().values().map(|d| d.delivered).sum();
+        self.connected_players.store(players as u64, Ordering::Relaxed);
+        self.bots.store(bots as u64, Ordering::Relaxed);
+        self.delivered.store(delivered as u64, Ordering::Relaxed);
+        self.max_blow_time.store(model.blow_times().into_iter().max().unwrap_or(0) as u64, Ordering::Relaxed);
+        self.stations.store(model.station_count() as u64, Ordering::Relaxed);
+        self.week.store(model.week() as u64, Ordering::Relaxed);
+    }
+
+    // Prometheus text format
+    pub fn render(&self) -> String {
+        let get = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP metrolti_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE metrolti_{} {}", name, kind);
+            let _ = writeln!(out, "metrolti_{} {}", name, value);
+        };
+        metric("ticks_total", "counter", "Ticks run.", get(&self.ticks).to_string());
+        metric("tick_seconds_total", "counter", "Time spent working on ticks.",
+               format!("{}", get(&self.tick_micros) as f64 / 1_000_000.));
+        metric("tick_overruns_total", "counter", "Ticks that took longer than the tick length.",
+               get(&self.tick_overruns).to_string());
+        metric("rooms", "gauge", "Games running.", ROOMS.count().to_string());
+        metric("connected_players", "gauge", "Players connected over websockets.", get(&self.connected_players).to_string());
+        metric("bots", "gauge", "Bots playing.", get(&self.bots).to_string());
+        metric("messages_in_total", "counter", "Actions received from players.", get(&self.messages_in).to_string());
+        metric("messages_out_total", "counter", "Messages sent to players.", get(&self.messages_out).to_string());
+        metric("bytes_out_total", "counter", "Bytes of messages sent to players.", get(&self.bytes_out).to_string());
+        metric("state_bytes", "gauge", "Size of the latest encoded game state.", get(&self.state_bytes).to_string());
+        metric("delivered_passengers", "gauge", "Passengers delivered this game.", get(&self.delivered).to_string());
+        metric("max_blow_time", "gauge", "Most overcrowded station's blow time.", get(&self.max_blow_time).to_string());
+        metric("stations", "gauge", "Stations on the map.", get(&self.stations).to_string());
+        metric("week", "gauge", "Current week of the game.", get(&self.week).to_string());
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maps;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.record_tick(Duration::from_millis(20), false);
+        metrics.record_tick(Duration::from_millis(40), true);
+        metrics.message_out(100);
+        metrics.message_out(50);
+        metrics.record_game(2, 1, &maps::builtin_maps()[0].build_model());
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE metrolti_ticks_total counter\nmetrolti_ticks_total 2\n"));
+        assert!(text.contains("\nmetrolti_tick_seconds_total 0.06\n"));
+        assert!(text.contains("\nmetrolti_tick_overruns_total 1\n"));
+        assert!(text.contains("\nmetrolti_messages_out_total 2\n"));
+        assert!(text.contains("\nmetrolti_bytes_out_total 150\n"));
+        assert!(text.contains("\nmetrolti_connected_players 2\n"));
+        assert!(text.contains("\nmetrolti_stations 3\n"));
+        assert_eq!(14 * 3, text.lines().count());
+    }
+}
@@ -1,29 +1,114 @@
-use std::collections::{ HashMap, HashSet };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::mem;
+use std::sync::Arc;
 use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
 use rand::{Rng, thread_rng};
 
+use bot::{ self, Bot };
 use events::{ InputEvent };
-use game::Game;
+use game::{ Game, SteppedGame };
+use maps::{ self, MapDefinition };
+use metrics::METRICS;
+use rooms::{ ROOMS, RoomId, RoomSummary };
 use player_id::*;
 use player::Player;
+use out_queue::LagStats;
+use protocol::{ self, Encoding, Frame };
+use rate_limit::TickBucket;
+use ruleset::{ self, Competitive, LineAllowance, Ruleset };
+use scoring::{ self, ScoringPolicy, LastMile };
+use spawning::Spawner;
 use ticks::*;
 use randoms::*;
+use results::{ self, GameEnd, GameResult, PlayerResult, ResultsStore };
+use visibility::{ self, Visibility };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum PlayerAction {
     StartGame,
+    SelectMap(String),
+    SelectScoring(String),
+    SelectVisibility(String),
+    SelectRuleset(String),
+    AddBot(String),
+    SetName(String),
+    // 0 turns teams off
+    SetTeamCount(u8),
+    JoinTeam(TeamId),
+    Chat(String),
+    Emote(Emote),
     NewLine(StationId, StationId),
     InsertAtLineBeginning(LineId, StationId),
     InsertAtLineEnd(LineId, StationId),
     InsertBetweenStations(LineId, StationId, StationId, StationId),
 }
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Emote {
+    Wave,
+    Cheer,
+    Laugh,
+    Groan,
+    ThumbsUp,
+}
+
+impl Emote {
+    // Emotes go out as chat, so every client can show them
+    pub fn text(&self) -> &'static str {
+        match *self {
+            Emote::Wave => "\u{1F44B}",
+            Emote::Cheer => "\u{1F389}",
+            Emote::Laugh => "\u{1F602}",
+            Emote::Groan => "\u{1F629}",
+            Emote::ThumbsUp => "\u{1F44D}",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub enum StateUpdate {
-    LobbyCount(u8),
+    LobbyCount(u16),
     GameState(MetroModel),
     You(PlayerId),
+    LobbySettings {
+        maps: Vec<String>,
+        map: String,
+        scoring_policies: Vec<String>,
+        scoring: String,
+        visibilities: Vec<String>,
+        visibility: String,
+        rulesets: Vec<String>,
+        ruleset: String,
+        bot_kinds: Vec<String>,
+        host: Option<PlayerId>,
+        team_count: u8,
+        teams: HashMap<PlayerId, TeamId>,
+    },
+    // Something the player did was refused, such as sending actions too fast
+    Warning(String),
+    // Sent once when a game ends, with every score revealed
+    GameOver(GameResult),
+    Chat {
+        from: PlayerId,
+        text: String,
+        tick: u64,
+    },
+}
+
+impl StateUpdate {
+    // Updates that replace everything an earlier one of the same kind said,
+    // so a player who has fallen behind only needs the latest
+    pub fn snapshot_kind(&self) -> Option<&'static str> {
+        match *self {
+            StateUpdate::LobbyCount(_) => Some("LobbyCount"),
+            StateUpdate::GameState(_) => Some("GameState"),
+            StateUpdate::LobbySettings { .. } => Some("LobbySettings"),
+            StateUpdate::Warning(_) => Some("Warning"),
+            StateUpdate::You(_) | StateUpdate::GameOver(_) | StateUpdate::Chat { .. } => None,
+        }
+    }
 }
 
 // This would probably be better off with state-handling trait and types
@@ -33,23 +118,70 @@ enum MGameState {
     Game,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Hash)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize, Hash, JsonSchema)]
 pub enum StationType {
     Circle,
     Triangle,
     Square,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Hash, JsonSchema)]
 pub struct StationId(pub usize);
 
 pub type Point = (f32, f32);
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum Terrain {
+    Lake { centre: Point, radius: f32 },
+    River { from: Point, to: Point, width: f32 },
+}
+
+impl Terrain {
+    pub fn covers(&self, pos: &Point) -> bool {
+        match *self {
+            Terrain::Lake { centre, radius } => square_distance(pos, &centre) < radius.powi(2),
+            Terrain::River { from, to, width } => distance_to_segment(pos, &from, &to) < width / 2.,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
+pub struct JourneyLeg {
+    pub line: LineId,
+    pub player: PlayerId,
+    pub distance: f32,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
+pub struct Passenger {
+    destination: StationType,
+    origin: StationId,
+    spawn_tick: u64,
+    transfers: u16,
+    legs: Vec<JourneyLeg>,
+}
+
+impl Passenger {
+    pub fn new(destination: StationType, origin: StationId, spawn_tick: u64) -> Self {
+        Self {
+            destination: destination,
+            origin: origin,
+            spawn_tick: spawn_tick,
+            transfers: 0,
+            legs: Vec::new(),
+        }
+    }
+
+    pub fn legs(&self) -> &[JourneyLeg] {
+        &self.legs
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub struct Station {
     t: StationType,
     position: Point,
-    passengers: Vec<StationType>,
+    passengers: Vec<Passenger>,
     blow_time: u32,
 }
 
@@ -62,19 +194,34 @@ impl Station {
             blow_time: 0u32,
         }
     }
+
+    pub fn station_type(&self) -> &StationType {
+        &self.t
+    }
+
+    pub fn position(&self) -> Point {
+        self.position
+    }
+
+    pub fn passenger_count(&self) -> usize {
+        self.passengers.len()
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub struct Edge {
     origin: StationId,
     destination: StationId,
     via_point: Point,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct LineId(pub usize);
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct TeamId(pub u8);
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub struct Line {
     colour: (f64, f64, f64),
     edges: Vec<Edge>,
@@ -115,7 +262,22 @@ impl Line {
         GetEdgeResult::LocNotFound
     }
 
-    fn is_valid_to_add_station(&self, station_id: &StationId) -> bool {
+    pub fn owner(&self) -> &PlayerId {
+        &self.owning_player
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    pub fn is_loop(&self) -> bool {
+        match (self.edges.first(), self.edges.last()) {
+            (Some(first), Some(last)) => first.origin == last.destination,
+            _ => false,
+        }
+    }
+
+    pub fn is_valid_to_add_station(&self, station_id: &StationId) -> bool {
         if self.edges.len() == 0 { return false; }
         if self.edges[0].origin == self.edges[self.edges.len() - 1].destination { return false; }
         for e in self.edges.iter() {
@@ -126,7 +288,7 @@ impl Line {
         true
     }
 
-    fn all_stations(&self) -> Vec<&StationId> {
+    pub fn all_stations(&self) -> Vec<&StationId> {
         if self.edges.len() == 0 { return Vec::new() }
         let mut v = Vec::new();
         v.push(&self.edges[0].origin);
@@ -164,10 +326,10 @@ impl Line {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TrainId(pub usize);
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub struct Train {
     on_line: LineId,
     position: Point,
@@ -175,7 +337,7 @@ pub struct Train {
     forward: bool,
     between_stations: (StationId, StationId),
     speed: f32,
-    passengers: Vec<StationType>,
+    passengers: Vec<Passenger>,
     passenger_wait: Option<u16>,
 }
 
@@ -192,6 +354,18 @@ impl Train {
             passenger_wait: None,
         }
     }
+
+    pub fn line(&self) -> &LineId {
+        &self.on_line
+    }
+
+    pub fn position(&self) -> Point {
+        self.position
+    }
+
+    pub fn passenger_count(&self) -> usize {
+        self.passengers.len()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -208,7 +382,57 @@ enum PassengerAction {
     Boarding,
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub enum DeliveryScoring {
+    #[default]
+    Flat,
+    // Up to `max_bonus` extra points, shrinking to none for journeys that
+    // take `target_ticks` or longer
+    Speed { target_ticks: u64, max_bonus: u16 },
+}
+
+impl DeliveryScoring {
+    fn points(&self, journey_ticks: u64) -> f32 {
+        match *self {
+            DeliveryScoring::Flat => 1.,
+            DeliveryScoring::Speed { target_ticks, max_bonus } => {
+                if target_ticks == 0 || journey_ticks >= target_ticks {
+                    return 1.;
+                }
+                let speed = (target_ticks - journey_ticks) as f64 / target_ticks as f64;
+                1. + (max_bonus as f64 * speed).round() as f32
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Delivery {
+    pub passenger: Passenger,
+    pub journey_ticks: u64,
+    pub points: f32,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Default, JsonSchema)]
+pub struct DeliveryStats {
+    pub delivered: u32,
+    pub total_journey_ticks: u64,
+    pub total_transfers: u32,
+    pub average_journey_ticks: f64,
+    pub average_transfers: f64,
+}
+
+impl DeliveryStats {
+    fn record(&mut self, journey_ticks: u64, transfers: u16) {
+        self.delivered += 1;
+        self.total_journey_ticks += journey_ticks;
+        self.total_transfers += transfers as u32;
+        self.average_journey_ticks = self.total_journey_ticks as f64 / self.delivered as f64;
+        self.average_transfers = self.total_transfers as f64 / self.delivered as f64;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, JsonSchema)]
 pub struct MetroModel {
     stations: Vec<Station>,
     lines: Vec<Line>,
@@ -219,7 +443,22 @@ pub struct MetroModel {
     max_x: f32,
     max_y: f32,
     time_to_blow: u32,
-    scores: HashMap<PlayerId, u16>,
+    scores: HashMap<PlayerId, f32>,
+    // The same scores pooled by team, as they come in
+    team_scores: HashMap<TeamId, f32>,
+    week: u32,
+    terrain: Vec<Terrain>,
+    tick: u64,
+    delivery_scoring: DeliveryScoring,
+    delivery_stats: HashMap<PlayerId, DeliveryStats>,
+    // Each player's latest ping, in milliseconds
+    round_trips: HashMap<PlayerId, u32>,
+    // Empty unless it's a team game
+    teams: HashMap<PlayerId, TeamId>,
+    // Whether everyone builds on every line, as in co-op
+    shared_network: bool,
+    #[serde(skip)]
+    deliveries: Vec<Delivery>,
 }
 
 impl MetroModel {
@@ -235,7 +474,224 @@ impl MetroModel {
             max_y: 500.,
             time_to_blow: 1350u32,
             scores: HashMap::new(),
+            team_scores: HashMap::new(),
+            week: 0,
+            terrain: Vec::new(),
+            tick: 0,
+            delivery_scoring: DeliveryScoring::Flat,
+            delivery_stats: HashMap::new(),
+            round_trips: HashMap::new(),
+            teams: HashMap::new(),
+            shared_network: false,
+            deliveries: Vec::new(),
+        }
+    }
+
+    pub fn take_deliveries(&mut self) -> Vec<Delivery> {
+        self.deliveries.drain(..).collect()
+    }
+
+    pub fn delivery_scoring(&self) -> &DeliveryScoring {
+        &self.delivery_scoring
+    }
+
+    pub fn set_delivery_scoring(&mut self, scoring: DeliveryScoring) {
+        self.delivery_scoring = scoring;
+    }
+
+    pub fn add_score(&mut self, player: PlayerId, points: f32) {
+        *self.scores.entry(player).or_insert(0.) += points;
+        if let Some(team) = self.team_of(&player) {
+            *self.team_scores.entry(team).or_insert(0.) += points;
+        }
+    }
+
+    pub fn bounds(&self) -> (Point, Point) {
+        ((self.min_x, self.min_y), (self.max_x, self.max_y))
+    }
+
+    pub fn set_bounds(&mut self, (min_x, min_y): Point, (max_x, max_y): Point) {
+        self.min_x = min_x;
+        self.min_y = min_y;
+        self.max_x = max_x;
+        self.max_y = max_y;
+    }
+
+    pub fn stations(&self) -> &[Station] {
+        &self.stations
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn trains(&self) -> &[Train] {
+        &self.trains
+    }
+
+    pub fn add_line(&mut self, player: PlayerId, colour: (f64, f64, f64)) -> LineId {
+        self.lines.push(Line { edges: Vec::new(), colour: colour, owning_player: player });
+        LineId(self.lines.len() - 1)
+    }
+
+    pub fn add_station(&mut self, t: StationType, position: Point) -> StationId {
+        self.stations.push(Station::new(t, position));
+        StationId(self.stations.len() - 1)
+    }
+
+    // Someone at `station` wanting a station of another type
+    pub fn add_passenger(&mut self, station: &StationId, destination: StationType) {
+        let tick = self.tick;
+        if let Some(s) = self.get_station_mut(station) {
+            if s.t != destination {
+                s.passengers.push(Passenger::new(destination, station.clone(), tick));
+            }
+        }
+    }
+
+    pub fn add_terrain(&mut self, terrain: Terrain) {
+        self.terrain.push(terrain);
+    }
+
+    pub fn station_count(&self) -> usize {
+        self.stations.len()
+    }
+
+    // The first station that's been overcrowded long enough to end the game
+    pub fn overcrowded_station(&self) -> Option<StationId> {
+        self.stations.iter().position(|s| s.blow_time >= self.time_to_blow).map(StationId)
+    }
+
+    pub fn blow_times(&self) -> Vec<u32> {
+        self.stations.iter().map(|s| s.blow_time).collect()
+    }
+
+    pub fn scores(&self) -> &HashMap<PlayerId, f32> {
+        &self.scores
+    }
+
+    pub fn delivery_stats(&self) -> &HashMap<PlayerId, DeliveryStats> {
+        &self.delivery_stats
+    }
+
+    pub fn round_trips(&self) -> &HashMap<PlayerId, u32> {
+        &self.round_trips
+    }
+
+    pub fn set_round_trip(&mut self, player: PlayerId, round_trip: Duration) {
+        let ms = round_trip.as_secs() as u32 * 1000 + round_trip.subsec_millis();
+        self.round_trips.insert(player, ms);
+    }
+
+    pub fn teams(&self) -> &HashMap<PlayerId, TeamId> {
+        &self.teams
+    }
+
+    pub fn team_of(&self, player: &PlayerId) -> Option<TeamId> {
+        self.teams.get(player).cloned()
+    }
+
+    // Players on the same team share their lines, and in co-op everyone does
+    pub fn same_side(&self, a: &PlayerId, b: &PlayerId) -> bool {
+        self.shared_network || a == b || (self.team_of(a).is_some() && self.team_of(a) == self.team_of(b))
+    }
+
+    // Everything delivered by anyone, which is the score in co-op
+    pub fn total_delivered(&self) -> u32 {
+        self.delivery_stats.values().map(|d| d.delivered).sum()
+    }
+
+    pub fn can_edit(&self, player: &PlayerId, line: &LineId) -> bool {
+        match self.get_line(line) {
+            Some(l) => self.same_side(player, &l.owning_player),
+            None => false,
+        }
+    }
+
+    // Empty unless it's a team game
+    pub fn team_scores(&self) -> &HashMap<TeamId, f32> {
+        &self.team_scores
+    }
+
+    pub fn week(&self) -> u32 {
+        self.week
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The model as `viewer` is allowed to see it. Stations and lines keep
+    /// their ids so actions sent back still point at the right ones.
+    pub fn view_for(&self, viewer: &PlayerId, visibility: &Visibility) -> MetroModel {
+        let mut view = self.clone();
+        match *visibility {
+            Visibility::Full => {}
+            Visibility::HiddenScores => view.hide_other_scores(viewer),
+            Visibility::FogOfWar { radius } => view.cover_in_fog(viewer, radius),
+        }
+        view
+    }
+
+    fn hide_other_scores(&mut self, viewer: &PlayerId) {
+        let teams = self.teams.clone();
+        let visible = |p: &PlayerId| p == viewer || (teams.contains_key(p) && teams.get(p) == teams.get(viewer));
+        self.scores.retain(|p, _| visible(p));
+        self.delivery_stats.retain(|p, _| visible(p));
+        let own_team = teams.get(viewer).cloned();
+        self.team_scores.retain(|t, _| Some(*t) == own_team);
+    }
+
+    fn cover_in_fog(&mut self, viewer: &PlayerId, radius: f32) {
+        let mut sights = Vec::new();
+        for line in self.lines.iter().filter(|l| self.same_side(&l.owning_player, viewer)) {
+            for s in line.all_stations() {
+                sights.extend(self.get_station_pos(s));
+            }
+        }
+        let in_sight = |pos: &Point| sights.iter().any(|s| square_distance(s, pos) <= radius.powi(2));
+
+        let hidden: Vec<bool> = self.stations.iter().map(|s| !in_sight(&s.position)).collect();
+        for (station, &hidden) in self.stations.iter_mut().zip(hidden.iter()) {
+            if hidden {
+                station.passengers.clear();
+                station.blow_time = 0;
+            }
+        }
+        let own_lines: Vec<bool> = self.lines.iter().map(|l| self.same_side(&l.owning_player, viewer)).collect();
+        for (line, _) in self.lines.iter_mut().zip(own_lines.iter()).filter(|&(_, &own)| !own) {
+            let hidden = |s: &StationId| hidden.get(s.0).cloned().unwrap_or(true);
+            line.edges.retain(|e| !hidden(&e.origin) || !hidden(&e.destination));
+        }
+        self.trains.retain(|t| own_lines[t.on_line.0] || in_sight(&t.position));
+    }
+
+    /// Shrinks the playable area to just around the current stations.
+    pub fn fit_bounds_to_stations(&mut self, margin: f32) {
+        if self.stations.is_empty() {
+            return;
+        }
+        let (mut min_x, mut min_y) = self.stations[0].position;
+        let (mut max_x, mut max_y) = self.stations[0].position;
+        for s in self.stations.iter() {
+            let (x, y) = s.position;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
         }
+        self.min_x = min_x - margin;
+        self.min_y = min_y - margin;
+        self.max_x = max_x + margin;
+        self.max_y = max_y + margin;
+    }
+
+    /// Grows the playable area on every side, never past `limit` from the origin.
+    pub fn expand_bounds(&mut self, amount: f32, limit: f32) {
+        self.min_x = (self.min_x - amount).max(-limit);
+        self.min_y = (self.min_y - amount).max(-limit);
+        self.max_x = (self.max_x + amount).min(limit);
+        self.max_y = (self.max_y + amount).min(limit);
     }
     pub fn get_station(&self, id: &StationId) -> Option<&Station> {
         let &StationId(index) = id;
@@ -298,6 +754,7 @@ impl MetroModel {
         if let Some(_wait) = train.passenger_wait {
             return;
         }
+        let start = train.position;
         let point_proximity = train.speed;
         let dx = train.heading.0 - train.position.0;
         let dy = train.heading.1 - train.position.1;
@@ -314,6 +771,12 @@ impl MetroModel {
         } else if dist > 0. {
             train.position = train.heading.clone();
         } 
+        let moved = square_distance(&start, &train.position).sqrt();
+        for p in train.passengers.iter_mut() {
+            if let Some(leg) = p.legs.last_mut() {
+                leg.distance += moved;
+            }
+        }
     }
 
     fn train_reached_destination(&self, id: &TrainId) -> bool {
@@ -377,7 +840,7 @@ impl MetroModel {
     fn passengers_who_want_to_alight(&self, train: &Train, station_id: &StationId) -> Vec<StationType> {
         let mut on_train = HashSet::with_capacity(6);
         for p in train.passengers.iter() {
-            on_train.insert(p);
+            on_train.insert(&p.destination);
         }
 
         let mut deliverable = HashSet::new();
@@ -418,14 +881,14 @@ impl MetroModel {
                 }
             }
         }
-        deliverable.iter().cloned().collect()
+        sorted(deliverable)
     }
 
     fn passengers_who_want_to_board(&self, train: &Train, station_id: &StationId) -> Vec<StationType> {
         let mut at_station = HashSet::with_capacity(6);
         if let Some(station) = self.get_station(station_id) {
             for p in station.passengers.iter() {
-                at_station.insert(p);
+                at_station.insert(&p.destination);
             }
         }
 
@@ -493,7 +956,7 @@ impl MetroModel {
             }
         }
 
-        deliverable.iter().cloned().collect()
+        sorted(deliverable)
     }
 
     fn station_passenger(&self, id: &TrainId) -> Option<(StationType, PassengerAction)> {
@@ -508,7 +971,7 @@ impl MetroModel {
                         if t.position != s.position {
                             return None;
                         }
-                        if t.passengers.contains(&s.t) {
+                        if t.passengers.iter().any(|p| p.destination == s.t) {
                             return Some((s.t.clone(), PassengerAction::Destination))
                         }
                         if t.passengers.len() > 0 {
@@ -533,26 +996,45 @@ impl MetroModel {
         if can_transfer {
             match self.station_passenger(id) {
                 Some((passenger, PassengerAction::Destination)) =>  {
-                    self.get_train_mut(id)
-                        .map(|t: &mut Train| remove_first(&mut t.passengers, &passenger));
+                    let delivered = self.get_train_mut(id)
+                        .and_then(|t: &mut Train| take_passenger(&mut t.passengers, &passenger));
                     let owning_player = self.get_train(id).and_then(|t| self.get_line(&t.on_line)).map(|l| l.owning_player);
-                    if let Some(p) = owning_player {
-                        *self.scores.entry(p).or_insert(0) += 1;
+                    if let (Some(p), Some(delivered)) = (owning_player, delivered) {
+                        let journey_ticks = self.tick - delivered.spawn_tick;
+                        let points = self.delivery_scoring.points(journey_ticks);
+                        self.delivery_stats.entry(p).or_default()
+                            .record(journey_ticks, delivered.transfers);
+                        self.deliveries.push(Delivery {
+                            passenger: delivered,
+                            journey_ticks: journey_ticks,
+                            points: points,
+                        });
                     }
                 }
                 Some((passenger, PassengerAction::Boarding)) =>  {
-                    self.get_at_station(id)
-                        .and_then(|s_id| self.get_station_mut(&s_id))
-                        .map(|s| remove_first(&mut s.passengers, &passenger));
-                    self.get_train_mut(id)
-                        .map(|t: &mut Train| t.passengers.push(passenger));
+                    // Nobody leaves the station without a line to ride
+                    let line = self.get_train(id)
+                        .and_then(|t| self.get_line(&t.on_line).map(|l| (t.on_line.clone(), l.owning_player)));
+                    if let Some((line_id, owner)) = line {
+                        let boarding = self.get_at_station(id)
+                            .and_then(|s_id| self.get_station_mut(&s_id))
+                            .and_then(|s| take_passenger(&mut s.passengers, &passenger));
+                        if let Some(mut boarding) = boarding {
+                            boarding.legs.push(JourneyLeg { line: line_id, player: owner, distance: 0. });
+                            self.get_train_mut(id)
+                                .map(|t: &mut Train| t.passengers.push(boarding));
+                        }
+                    }
                 }
                 Some((passenger, PassengerAction::Change)) =>  {
-                    self.get_train_mut(id)
-                        .map(|t: &mut Train| remove_first(&mut t.passengers, &passenger));
-                    self.get_at_station(id)
-                        .and_then(|s_id| self.get_station_mut(&s_id))
-                        .map(|s| s.passengers.push(passenger));
+                    let changing = self.get_train_mut(id)
+                        .and_then(|t: &mut Train| take_passenger(&mut t.passengers, &passenger));
+                    if let Some(mut changing) = changing {
+                        changing.transfers += 1;
+                        self.get_at_station(id)
+                            .and_then(|s_id| self.get_station_mut(&s_id))
+                            .map(|s| s.passengers.push(changing));
+                    }
                 }
                 None => {}
             }
@@ -618,12 +1100,13 @@ impl MetroModel {
             let id = TrainId(i);
             self.update_train(&id);
         }
+        self.tick += 1;
     }
 
     fn get_player_unused_line_id(&self, player: &PlayerId) -> Option<LineId> {
         for i in 0..self.lines.len() {
             if self.lines[i].edges.len() > 0 { continue; }
-            if self.lines[i].owning_player == *player {
+            if self.same_side(&self.lines[i].owning_player, player) {
                 return Some(LineId(i));
             }
         }
@@ -645,6 +1128,10 @@ impl MetroModel {
     }
 
     pub fn start_new_line(&mut self, player: &PlayerId, origin: &StationId, dest: &StationId) -> Option<LineId> {
+        // Station ids come from players, who can send anything
+        if self.get_station(origin).is_none() || self.get_station(dest).is_none() {
+            return None;
+        }
         let via = self.get_via_point_between(origin, dest);
         if let Some(line_id) = self.get_player_unused_line_id(player) {
             if let Some(line) = self.get_line_mut(&line_id) {
@@ -656,6 +1143,9 @@ impl MetroModel {
     }
 
     pub fn insert_before_line(&mut self, line_id: &LineId, new_station: &StationId) {
+        if self.get_station(new_station).is_none() {
+            return;
+        }
         let line_origin_if_valid =
             if let Some(line) = self.get_line(&line_id) {
                 if line.is_valid_to_add_station(new_station) {
@@ -669,6 +1159,9 @@ impl MetroModel {
     }
 
     pub fn insert_after_line(&mut self, line_id: &LineId, new_station: &StationId) {
+        if self.get_station(new_station).is_none() {
+            return;
+        }
         let line_dest_if_valid =
             if let Some(line) = self.get_line(&line_id) {
                 if line.is_valid_to_add_station(new_station) {
@@ -689,98 +1182,266 @@ impl MetroModel {
         if y < &self.min_y || y > &self.max_y {
             return false;
         }
-        let max_square_distance = ((self.station_size + self.station_size) as f32).powi(2);
-        for existing_station in self.stations.iter() {
-            let (ref other_x, ref other_y) = existing_station.position;
-            let diff_x = x - other_x;
-            let diff_y = y - other_y;
-            let square_distance = diff_x.powi(2) + diff_y.powi(2);
-            if square_distance < max_square_distance {
-                return false;
+        if self.is_on_terrain(pos) {
+            return false;
+        }
+        let min_distance = (self.station_size + self.station_size) as f32;
+        match self.distance_to_nearest_station(pos) {
+            Some(d) => d >= min_distance,
+            None => true,
+        }
+    }
+
+    pub fn is_on_terrain(&self, pos: &Point) -> bool {
+        self.terrain.iter().any(|t| t.covers(pos))
+    }
+
+    pub fn distance_to_nearest_station(&self, pos: &Point) -> Option<f32> {
+        self.stations.iter()
+            .map(|s| square_distance(pos, &s.position))
+            .fold(None, |closest: Option<f32>, d| Some(closest.map_or(d, |c| c.min(d))))
+            .map(|d| d.sqrt())
+    }
+
+    pub fn distance_to_nearest_edge(&self, pos: &Point) -> Option<f32> {
+        let mut closest: Option<f32> = None;
+        for line in self.lines.iter() {
+            for e in line.edges.iter() {
+                let origin = self.get_station_pos(&e.origin).unwrap_or(e.via_point);
+                let destination = self.get_station_pos(&e.destination).unwrap_or(e.via_point);
+                let d = distance_to_segment(pos, &origin, &e.via_point)
+                    .min(distance_to_segment(pos, &e.via_point, &destination));
+                closest = Some(closest.map_or(d, |c| c.min(d)));
             }
         }
-        true
+        closest
     }
 }
 
-fn remove_first<T: Eq>(v: &mut Vec<T>, t: &T) {
-    let mut delete_idx = None;
-    for i in 0..(v.len()) {
-        if v[i] == *t {
-            delete_idx = Some(i);
-            break;
-        }
+pub fn square_distance(a: &Point, b: &Point) -> f32 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+fn distance_to_segment(p: &Point, a: &Point, b: &Point) -> f32 {
+    let length_sq = square_distance(a, b);
+    if length_sq == 0. {
+        return square_distance(p, a).sqrt();
     }
-    delete_idx.map(|i| v.remove(i));
+    let t = (((p.0 - a.0) * (b.0 - a.0) + (p.1 - a.1) * (b.1 - a.1)) / length_sq).clamp(0., 1.);
+    let projection = (a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1));
+    square_distance(p, &projection).sqrt()
+}
+
+// Hash set order changes from run to run, which would stop seeded games
+// from repeating
+fn sorted(set: HashSet<StationType>) -> Vec<StationType> {
+    let mut v: Vec<StationType> = set.into_iter().collect();
+    v.sort();
+    v
+}
+
+fn take_passenger(v: &mut Vec<Passenger>, destination: &StationType) -> Option<Passenger> {
+    v.iter()
+        .position(|p| p.destination == *destination)
+        .map(|i| v.remove(i))
+}
+
+enum Outgoing {
+    To(PlayerId, StateUpdate),
+    Broadcast(Vec<PlayerId>, StateUpdate),
 }
 
 pub struct MetroGame<T: Ticker, R: Random> {
     state: MGameState,
     r: Receiver<InputEvent>,
     player_out: HashMap<PlayerId, Player>,
+    outbox: Vec<Outgoing>,
+    bots: Vec<(PlayerId, Box<dyn Bot + Send>)>,
+    next_bot_id: u16,
     ticker: T,
     model: MetroModel,
     random: R,
+    // Set once the game is running as a room, for its log lines
+    room: Option<RoomId>,
+    // Ticks since the game was made, lobby included. The model's count
+    // starts again with every game.
+    clock: u64,
 
-    ticks_since_last_station: u64,
-    min_ticks_between_stations: u64,
-    base_station_chance: f64,
-    station_chance_per_tick: f64,
-
-    ticks_since_last_passenger: Vec<u64>,
-    min_ticks_between_passengers: u64,
-    base_passenger_chance: f64,
-    passenger_chance_per_tick: f64,
+    spawner: Spawner,
 
     ticks_per_week: u64,
     ticks_since_weekend: u64,
 
+    map_growth_per_week: f32,
+    max_map_extent: f32,
+
     max_lines_per_player: u8,
+    lines_per_week: u8,
+
+    maps: Vec<MapDefinition>,
+    selected_map: usize,
+
+    scoring: Box<dyn ScoringPolicy + Send>,
+    visibility: Visibility,
+    ruleset: Box<dyn Ruleset + Send>,
+
+    names: HashMap<PlayerId, String>,
+    // No teams when it's 0. Who is on which lives in the model.
+    team_count: u8,
+    // The latest chat, for players who join part way through. Team chat is
+    // kept with its team.
+    chat_history: VecDeque<(Option<TeamId>, StateUpdate)>,
+    chat_limits: HashMap<PlayerId, TickBucket>,
+    // The last game to end, until it's been taken to be recorded
+    finished: Option<GameResult>,
 }
 
+const MAX_NAME_LENGTH: usize = 24;
+const MAX_TEAMS: u8 = 4;
+// How often the room's state is copied out for the web API, once a second
+const ROOM_STATE_TICKS: u64 = 30;
+// Every bot thinks every tick, so a lobby only gets so many
+const MAX_BOTS: usize = 8;
+const MAX_CHAT_LENGTH: usize = 200;
+const CHAT_HISTORY: usize = 20;
+// Five messages at once, then one every two seconds at 30 ticks a second
+const CHAT_BURST: f64 = 5.;
+const CHAT_TICKS_PER_MESSAGE: u64 = 60;
+
 impl<T: Ticker, R: Random> Game<T, R> for MetroGame<T, R> {
     fn new(event_loop: Receiver<InputEvent>, ticker: T, random: R) -> Self {
         MetroGame {
             state: MGameState::Lobby,
             r: event_loop,
             player_out: HashMap::new(),
+            outbox: Vec::new(),
+            bots: Vec::new(),
+            // Connected players count up from 0, so bots count down from the top
+            next_bot_id: u16::MAX,
             ticker: ticker,
             model: MetroModel::new(),
             random: random,
+            room: None,
+            clock: 0,
 
-            ticks_since_last_station: 0,
-            min_ticks_between_stations: 30,
-            base_station_chance: 0.00005,
-            station_chance_per_tick: 0.000005,
-
-            ticks_since_last_passenger: Vec::new(),
-            min_ticks_between_passengers: 30,
-            base_passenger_chance: 0.00005,
-            passenger_chance_per_tick: 0.000005,
+            spawner: Spawner::new(),
 
             ticks_per_week: 4200,
             ticks_since_weekend: 0,
 
+            map_growth_per_week: 75.,
+            max_map_extent: 500.,
+
             max_lines_per_player: 7,
+            lines_per_week: 1,
+
+            maps: maps::builtin_maps(),
+            selected_map: 0,
+
+            scoring: Box::new(LastMile),
+            visibility: Visibility::Full,
+            ruleset: Box::new(Competitive),
+
+            names: HashMap::new(),
+            team_count: 0,
+            chat_history: VecDeque::new(),
+            chat_limits: HashMap::new(),
+            finished: None,
         }
     }
     fn main(&mut self) {
+        let room = ROOMS.open();
+        self.room = Some(room.id());
+        let results = ResultsStore::from_env();
+        let mut state_published = false;
         self.ticker.start();
         loop {
             self.input();
             self.update();
             self.output();
+            METRICS.record_game(self.player_out.len(), self.bots.len(), &self.model);
+            room.publish_summary(self.room_summary());
+            // Copying the whole model is too much to do every tick for
+            // spectators who may not be there
+            if !self.state_is_public() {
+                if state_published {
+                    room.publish_state(None);
+                    state_published = false;
+                }
+            } else if !state_published || self.model.tick % ROOM_STATE_TICKS == 0 {
+                room.publish_state(self.public_state().map(Arc::new));
+                state_published = true;
+            }
+            if let Some(result) = self.take_result() {
+                if let Err(e) = results.record(&result) {
+                    warn!(room = self.room, error = e.as_str(); "Result not recorded");
+                }
+            }
             self.ticker.wait_until_next_tick();
         }
     }
 }
 
+impl<T: Ticker, R: Random> SteppedGame for MetroGame<T, R> {
+    fn step(&mut self, events: Vec<InputEvent>) -> Vec<(PlayerId, StateUpdate)> {
+        self.handle_events(events);
+        self.update();
+        self.queue_output();
+        let mut updates = Vec::new();
+        for out in self.outbox.drain(..) {
+            match out {
+                Outgoing::To(id, update) => updates.push((id, update)),
+                Outgoing::Broadcast(ids, update) => {
+                    for id in ids {
+                        updates.push((id, update.clone()));
+                    }
+                }
+            }
+        }
+        updates
+    }
+}
+
 impl<T: Ticker, R: Random> MetroGame<T, R> {
+    pub fn model(&self) -> &MetroModel {
+        &self.model
+    }
+
+    // In place of the built-in maps, say with ones loaded from disk
+    pub fn set_maps(&mut self, maps: Vec<MapDefinition>) {
+        self.maps = maps;
+        self.selected_map = 0;
+    }
+
     pub fn input(&mut self) {
-        let mut events : Vec<_> = self.r.try_iter().collect();
+        let events : Vec<_> = self.r.try_iter().collect();
+        self.handle_events(events);
+    }
+
+    fn handle_events(&mut self, mut events: Vec<InputEvent>) {
         for in_event in events.drain(..) {
             self.handle_event(in_event);
         }
+        if self.state == MGameState::Game {
+            self.bot_input();
+        }
+    }
+
+    fn bot_input(&mut self) {
+        let mut actions = Vec::new();
+        for &mut (ref id, ref mut bot) in self.bots.iter_mut() {
+            // Bots get the same view a connected player would
+            let view = match self.visibility {
+                Visibility::Full => None,
+                _ => Some(self.model.view_for(id, &self.visibility)),
+            };
+            for action in bot.act(id, view.as_ref().unwrap_or(&self.model)) {
+                actions.push(InputEvent::PlayerAction(*id, action));
+            }
+        }
+        for ev in actions {
+            self.handle_game_event(ev);
+        }
     }
     fn handle_event(&mut self, ev: InputEvent) {
         match self.state {
@@ -792,13 +1453,26 @@ impl<T: Ticker, R: Random> MetroGame<T, R> {
     fn handle_game_event(&mut self, ev: InputEvent) {
         match ev {
             InputEvent::Connection(p_id, p) => {
-                p.send_message(StateUpdate::You(p_id.clone()));
                 self.player_out.insert(p_id, p);
+                self.assign_team(p_id);
+                self.send_to(p_id, StateUpdate::You(p_id));
+                self.send_chat_history(p_id);
             }
             InputEvent::Disconnection(p_id) => {
                 self.player_out.remove(&p_id);
+                self.model.round_trips.remove(&p_id);
+                self.chat_limits.remove(&p_id);
+                if self.player_out.is_empty() {
+                    self.end_game(GameEnd::Abandoned);
+                }
+            }
+            InputEvent::RoundTrip(p_id, round_trip) => {
+                self.model.set_round_trip(p_id, round_trip);
             }
             InputEvent::PlayerAction(p_id, action) => { 
+                if !self.ruleset.validate_action(&self.model, &p_id, &action) {
+                    return;
+                }
                 match action {
                     PlayerAction::NewLine(src, tgt) => {
                         let new_id = self.model.start_new_line(&p_id, &src, &tgt);
@@ -814,7 +1488,9 @@ impl<T: Ticker, R: Random> MetroGame<T, R> {
                         self.model.insert_after_line(&line_id, &station_id);
                     }
                     PlayerAction::InsertBetweenStations(_, _, _, _) => {}
-                    PlayerAction::StartGame => {
+                    PlayerAction::Chat(text) => self.chat(p_id, &text),
+                    PlayerAction::Emote(emote) => self.chat(p_id, emote.text()),
+                    PlayerAction::StartGame | PlayerAction::SelectMap(_) | PlayerAction::SelectScoring(_) | PlayerAction::SelectVisibility(_) | PlayerAction::SelectRuleset(_) | PlayerAction::AddBot(_) | PlayerAction::SetName(_) | PlayerAction::SetTeamCount(_) | PlayerAction::JoinTeam(_) => {
                         // Game is already started
                     }
                 }
@@ -823,30 +1499,123 @@ impl<T: Ticker, R: Random> MetroGame<T, R> {
     }
 
     fn get_player_ids(&self) -> Vec<PlayerId> {
-        self.player_out.keys().cloned().collect()
+        let mut ids: Vec<PlayerId> = self.player_out.keys().cloned().collect();
+        ids.extend(self.bots.iter().map(|&(id, _)| id));
+        ids.sort();
+        ids
     }
     
     fn handle_lobby_event(&mut self, ev: InputEvent) {
         match ev {
             InputEvent::Connection(p_id, p) => {
-                p.send_message(StateUpdate::You(p_id.clone()));
                 self.player_out.insert(p_id, p);
+                self.send_to(p_id, StateUpdate::You(p_id));
+                if self.assign_team(p_id) {
+                    self.broadcast_lobby_settings();
+                } else {
+                    let settings = self.lobby_settings();
+                    self.send_to(p_id, settings);
+                }
+                self.send_chat_history(p_id);
             }
             InputEvent::Disconnection(p_id) => {
+                let old_host = self.host();
                 self.player_out.remove(&p_id);
+                self.names.remove(&p_id);
+                self.chat_limits.remove(&p_id);
+                self.model.round_trips.remove(&p_id);
+                let left_team = self.model.teams.remove(&p_id).is_some();
+                if self.host() != old_host || left_team {
+                    self.broadcast_lobby_settings();
+                }
+            }
+            InputEvent::RoundTrip(p_id, round_trip) => {
+                self.model.set_round_trip(p_id, round_trip);
             }
-            InputEvent::PlayerAction(_p_id, action) => {
+            InputEvent::PlayerAction(p_id, action) => {
                 match action {
                     PlayerAction::StartGame => { 
-                        self.state = MGameState::Game;
-                        self.model = MetroModel::new();
-                        self.model.stations.push(Station::new(StationType::Circle, (10., -30.)));
-                        self.model.stations.push(Station::new(StationType::Square, (-45., 70.)));
-                        self.model.stations.push(Station::new(StationType::Triangle, (300., 30.)));
-                        for player in self.get_player_ids() {
-                            self.add_line_for_player(&player);
+                        self.start_game();
+                    }
+                    PlayerAction::SelectMap(name) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if let Some(i) = self.maps.iter().position(|m| m.name == name) {
+                            self.selected_map = i;
+                            self.broadcast_lobby_settings();
+                        }
+                    }
+                    PlayerAction::SelectScoring(name) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if let Some(policy) = scoring::policy_by_name(&name) {
+                            self.scoring = policy;
+                            self.broadcast_lobby_settings();
                         }
                     }
+                    PlayerAction::SelectVisibility(name) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if let Some(v) = visibility::visibility_by_name(&name) {
+                            self.visibility = v;
+                            self.broadcast_lobby_settings();
+                        }
+                    }
+                    PlayerAction::SelectRuleset(name) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if let Some(r) = ruleset::ruleset_by_name(&name) {
+                            self.ruleset = r;
+                            self.broadcast_lobby_settings();
+                        }
+                    }
+                    PlayerAction::AddBot(kind) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if self.bots.len() >= MAX_BOTS {
+                            let warning = format!("A game can have at most {} bots", MAX_BOTS);
+                            self.send_to(p_id, StateUpdate::Warning(warning));
+                            return;
+                        }
+                        let next_bot_id = match self.next_bot_id.checked_sub(1) {
+                            Some(next) => next,
+                            None => return,
+                        };
+                        if let Some(b) = bot::bot_by_name(&kind) {
+                            let id = PlayerId::new(self.next_bot_id);
+                            self.next_bot_id = next_bot_id;
+                            self.bots.push((id, b));
+                            if self.assign_team(id) {
+                                self.broadcast_lobby_settings();
+                            }
+                        }
+                    }
+                    PlayerAction::SetName(name) => {
+                        let name = name.trim();
+                        if !name.is_empty() && name.chars().count() <= MAX_NAME_LENGTH {
+                            self.names.insert(p_id, name.to_string());
+                        }
+                    }
+                    PlayerAction::SetTeamCount(count) => {
+                        if Some(p_id) != self.host() {
+                            return;
+                        }
+                        if count == 0 || (2..=MAX_TEAMS).contains(&count) {
+                            self.set_team_count(count);
+                            self.broadcast_lobby_settings();
+                        }
+                    }
+                    PlayerAction::JoinTeam(team) if team.0 < self.team_count => {
+                        self.model.teams.insert(p_id, team);
+                        self.broadcast_lobby_settings();
+                    }
+                    PlayerAction::Chat(text) => self.chat(p_id, &text),
+                    PlayerAction::Emote(emote) => self.chat(p_id, emote.text()),
                     _ => {
                         // It's unlikelu that there will be any more events that
                         // have an effect in the lobby
@@ -856,25 +1625,240 @@ impl<T: Ticker, R: Random> MetroGame<T, R> {
         }
     }
 
-    fn add_line_for_player(&mut self, player_id: &PlayerId) {
-        let mut rng = thread_rng();
-        let new_line = Line { edges: Vec::new(), colour: (rng.gen(), rng.gen(), rng.gen()), owning_player: player_id.clone() };
-        self.model.lines.push(new_line);
+    fn chat(&mut self, from: PlayerId, text: &str) {
+        let text: String = text.trim().chars().filter(|c| !c.is_control()).collect();
+        if text.is_empty() {
+            return;
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            let warning = format!("Chat messages can be at most {} characters", MAX_CHAT_LENGTH);
+            self.send_to(from, StateUpdate::Warning(warning));
+            return;
+        }
+        let now = self.clock;
+        let allowed = self.chat_limits.entry(from)
+            .or_insert_with(|| TickBucket::new(CHAT_BURST, 1. / CHAT_TICKS_PER_MESSAGE as f64, now))
+            .take(now);
+        if !allowed {
+            self.send_to(from, StateUpdate::Warning("You're chatting too fast".to_string()));
+            return;
+        }
+        let message = StateUpdate::Chat { from: from, text: text, tick: self.model.tick };
+        // Everyone can talk in the lobby, but in a game teams keep to themselves
+        let team = match self.state {
+            MGameState::Lobby => None,
+            MGameState::Game => self.model.team_of(&from),
+        };
+        if self.chat_history.len() >= CHAT_HISTORY {
+            self.chat_history.pop_front();
+        }
+        self.chat_history.push_back((team, message.clone()));
+        match team {
+            Some(team) => {
+                let ids = self.connected_ids().into_iter().filter(|id| self.model.team_of(id) == Some(team)).collect();
+                self.outbox.push(Outgoing::Broadcast(ids, message));
+            }
+            None => self.broadcast(message),
+        }
     }
 
-    fn finish_week(&mut self) {
-        println!("Le weekend");
-        for player in self.get_player_ids() {
-            let mut player_line_count = 0;
-            for line in self.model.lines.iter() {
-                if line.owning_player == player {
-                    player_line_count += 1;
-                }
+    fn send_chat_history(&mut self, to: PlayerId) {
+        let team = self.model.team_of(&to);
+        for (for_team, message) in self.chat_history.clone() {
+            if for_team.is_none() || for_team == team {
+                self.send_to(to, message);
             }
-            if player_line_count < self.max_lines_per_player {
+        }
+    }
+
+    // Puts the player on the smallest team, if teams are on. Returns whether
+    // they were.
+    fn assign_team(&mut self, player: PlayerId) -> bool {
+        if self.team_count == 0 || self.model.teams.contains_key(&player) {
+            return false;
+        }
+        let mut sizes = vec![0; self.team_count as usize];
+        for team in self.model.teams.values() {
+            sizes[team.0 as usize] += 1;
+        }
+        let smallest = (0..sizes.len()).min_by_key(|&t| sizes[t]).unwrap_or(0);
+        self.model.teams.insert(player, TeamId(smallest as u8));
+        true
+    }
+
+    // Everyone is dealt out again, so the teams come out even
+    fn set_team_count(&mut self, count: u8) {
+        self.team_count = count;
+        self.model.teams.clear();
+        for player in self.get_player_ids() {
+            self.assign_team(player);
+        }
+    }
+
+    // The longest-connected player picks the map
+    fn host(&self) -> Option<PlayerId> {
+        self.player_out.keys().min().cloned()
+    }
+
+    // What anyone outside the game may know about it
+    fn room_summary(&self) -> RoomSummary {
+        RoomSummary {
+            id: 0,
+            phase: match self.state {
+                MGameState::Lobby => "lobby",
+                MGameState::Game => "game",
+            }.to_string(),
+            players: self.player_out.len(),
+            bots: self.bots.len(),
+            map: self.maps.get(self.selected_map).map(|m| m.name.clone()).unwrap_or_default(),
+            scoring: self.scoring.name().to_string(),
+            visibility: self.visibility.name().to_string(),
+            ruleset: self.ruleset.name().to_string(),
+            week: self.model.week,
+            tick: self.model.tick,
+            scores: if self.visibility == Visibility::Full { Some(self.model.scores.clone()) } else { None },
+            team_scores: if self.visibility == Visibility::Full { Some(self.model.team_scores.clone()) } else { None },
+        }
+    }
+
+    fn state_is_public(&self) -> bool {
+        self.state == MGameState::Game && self.visibility == Visibility::Full
+    }
+
+    fn public_state(&self) -> Option<MetroModel> {
+        if self.state_is_public() {
+            Some(self.model.clone())
+        } else {
+            None
+        }
+    }
+
+    fn lobby_settings(&self) -> StateUpdate {
+        StateUpdate::LobbySettings {
+            maps: self.maps.iter().map(|m| m.name.clone()).collect(),
+            map: self.maps.get(self.selected_map).map(|m| m.name.clone()).unwrap_or_default(),
+            scoring_policies: scoring::all_policies().iter().map(|p| p.name().to_string()).collect(),
+            scoring: self.scoring.name().to_string(),
+            visibilities: visibility::all_visibilities().iter().map(|v| v.name().to_string()).collect(),
+            visibility: self.visibility.name().to_string(),
+            rulesets: ruleset::all_rulesets().iter().map(|r| r.name().to_string()).collect(),
+            ruleset: self.ruleset.name().to_string(),
+            bot_kinds: bot::bot_kinds().iter().map(|k| k.to_string()).collect(),
+            host: self.host(),
+            team_count: self.team_count,
+            teams: self.model.teams.clone(),
+        }
+    }
+
+    fn broadcast_lobby_settings(&mut self) {
+        let settings = self.lobby_settings();
+        self.broadcast(settings);
+    }
+
+    fn start_game(&mut self) {
+        self.state = MGameState::Game;
+        self.spawner.reset();
+        self.random.reseed();
+        info!(room = self.room, seed = self.random.seed(); "Game started");
+        self.ticks_since_weekend = 0;
+        let round_trips = mem::take(&mut self.model.round_trips);
+        let teams = mem::take(&mut self.model.teams);
+        if let Some(map) = self.maps.get(self.selected_map).cloned() {
+            self.model = map.build_model();
+            self.map_growth_per_week = map.schedule.map_growth;
+            self.max_map_extent = map.schedule.max_extent;
+            self.ticks_per_week = map.schedule.ticks_per_week;
+            self.max_lines_per_player = map.schedule.max_lines_per_player;
+            self.lines_per_week = map.schedule.lines_per_week;
+            self.spawner.weights = map.spawn_weights;
+            self.spawner.placement.density = map.density;
+        } else {
+            self.model = MetroModel::new();
+        }
+        self.model.round_trips = round_trips;
+        self.model.teams = teams;
+        self.ruleset.reset();
+        self.model.shared_network = self.ruleset.shared_network();
+        // Players who share lines, like a team, start with one between them,
+        // held by the first of them
+        let mut started: Vec<PlayerId> = Vec::new();
+        for player in self.get_player_ids() {
+            if !started.iter().any(|p| self.model.same_side(p, &player)) {
                 self.add_line_for_player(&player);
+                started.push(player);
             }
         }
+    }
+
+    // Back to the lobby, telling everyone how it went
+    fn end_game(&mut self, end: GameEnd) {
+        info!(room = self.room, tick = self.model.tick, week = self.model.week, reason:% = end; "Game over");
+        let result = self.result(end);
+        self.state = MGameState::Lobby;
+        self.broadcast(StateUpdate::GameOver(result.clone()));
+        self.broadcast_lobby_settings();
+        self.finished = Some(result);
+    }
+
+    fn result(&self, end: GameEnd) -> GameResult {
+        // Players who left part way through still have their lines and score
+        let mut ids = self.get_player_ids();
+        ids.extend(self.model.lines.iter().map(|l| l.owning_player));
+        ids.extend(self.model.scores.keys().cloned());
+        ids.sort();
+        ids.dedup();
+        let players: Vec<PlayerResult> = ids.into_iter().map(|id| {
+            let bot = self.bots.iter().find(|&&(b, _)| b == id);
+            let lines: Vec<usize> = (0..self.model.lines.len())
+                .filter(|&i| self.model.lines[i].owning_player == id && !self.model.lines[i].is_unused())
+                .collect();
+            PlayerResult {
+                player: id,
+                name: match bot {
+                    Some((_, b)) => b.name().to_string(),
+                    None => self.names.get(&id).cloned().unwrap_or_else(|| results::unnamed(id)),
+                },
+                bot: bot.is_some(),
+                team: self.model.team_of(&id),
+                score: self.ruleset.score(&self.model, &id),
+                lines: lines.len(),
+                trains: self.model.trains.iter().filter(|t| lines.contains(&t.on_line.0)).count(),
+                delivered: self.model.delivery_stats.get(&id).map_or(0, |d| d.delivered),
+            }
+        }).collect();
+        let teams = results::team_totals(&players);
+        GameResult {
+            map: self.maps.get(self.selected_map).map(|m| m.name.clone()).unwrap_or_default(),
+            scoring: self.scoring.name().to_string(),
+            visibility: self.visibility.name().to_string(),
+            ruleset: self.ruleset.name().to_string(),
+            seed: self.random.seed(),
+            weeks: self.model.week,
+            ticks: self.model.tick,
+            ended: end,
+            players: players,
+            teams: teams,
+        }
+    }
+
+    // The last game to finish, if it hasn't been taken already
+    pub fn take_result(&mut self) -> Option<GameResult> {
+        self.finished.take()
+    }
+
+    fn add_line_for_player(&mut self, player_id: &PlayerId) {
+        let mut rng = thread_rng();
+        self.model.add_line(player_id.clone(), (rng.gen(), rng.gen(), rng.gen()));
+    }
+
+    fn finish_week(&mut self) {
+        info!(room = self.room, tick = self.model.tick, week = self.model.week + 1; "Le weekend");
+        let allowance = LineAllowance { per_week: self.lines_per_week, max: self.max_lines_per_player };
+        for player in self.ruleset.on_week_end(&self.model, &self.get_player_ids(), allowance) {
+            self.add_line_for_player(&player);
+        }
+        self.model.week += 1;
+        self.model.expand_bounds(self.map_growth_per_week, self.max_map_extent);
         self.ticks_since_weekend = 0;
     }
 
@@ -885,133 +1869,707 @@ impl<T: Ticker, R: Random> MetroGame<T, R> {
         }
     }
 
-    fn random_station_type(&self) -> StationType {
-        let roll = self.random.gen();
-        if roll < 0.4 {
-            StationType::Circle
-        } else if roll < 0.7 {
-            StationType::Square
-        } else {
-            StationType::Triangle
-        }
-    }
     pub fn update(&mut self) {
-        if let Some(spawnable_ticks) = self.ticks_since_last_station.checked_sub(self.min_ticks_between_stations) {
-            let chance = self.base_station_chance + self.station_chance_per_tick * spawnable_ticks as f64;
-            if self.random.gen() < chance {
-                let width = self.model.max_x - self.model.min_x;
-                let height = self.model.max_y - self.model.min_y;
-                let x = self.random.gen() as f32 * width + self.model.min_x;
-                let y = self.random.gen() as f32 * height + self.model.min_y;
-                if self.model.is_valid_station_pos(&(x, y)) {
-                    let station_type = self.random_station_type();
-                    self.model.stations.push(Station::new(station_type, (x, y)));
-                }
-                self.ticks_since_last_station = 0;
+        // A finished game stays as it ended until the next one starts
+        if self.state == MGameState::Game {
+            self.ruleset.on_tick(&mut self.model, &mut self.spawner, &self.random);
+            self.update_week();
+            self.model.update();
+            for delivery in self.model.take_deliveries() {
+                self.ruleset.on_delivery(&mut self.model, &delivery, &*self.scoring);
             }
-        }
-        self.ticks_since_last_station += 1;
-        self.ticks_since_last_passenger.resize(self.model.stations.len(), 0);
-        for i in 0..self.model.stations.len() {
-            if let Some(spawnable_ticks) = self.ticks_since_last_passenger[i].checked_sub(self.min_ticks_between_passengers) {
-                let chance = self.base_passenger_chance + self.passenger_chance_per_tick * spawnable_ticks as f64;
-                if self.random.gen() < chance {
-                    let station_type = self.random_station_type();
-                    let station = StationId(i);
-                    self.model.get_station_mut(&station)
-                        .map(|s| 
-                             if s.t != station_type {
-                                 s.passengers.push(station_type)
-                             });
-                    self.ticks_since_last_passenger[i] = 0;
-                }
+            if let Some(end) = self.ruleset.is_game_over(&self.model) {
+                self.end_game(end);
             }
-            self.ticks_since_last_passenger[i] += 1;
         }
-        self.update_week();
-        self.model.update();
+        self.clock += 1;
     }
     pub fn output(&mut self) {
+        self.queue_output();
+        self.flush();
+    }
+
+    fn queue_output(&mut self) {
         match self.state {
             MGameState::Lobby => self.lobby_output(),
             MGameState::Game => self.game_output(),
         }
     }
     pub fn game_output(&mut self) {
-        for p in self.player_out.values() {
-            p.send_message(StateUpdate::GameState(self.model.clone()));
+        if self.visibility == Visibility::Full {
+            let state = StateUpdate::GameState(self.model.clone());
+            self.broadcast(state);
+            return;
+        }
+        // Every player's view is different, so each is encoded on its own
+        for id in self.connected_ids() {
+            let view = self.model.view_for(&id, &self.visibility);
+            self.send_to(id, StateUpdate::GameState(view));
         }
     }
     pub fn lobby_output(&mut self) {
-        let connected = self.player_out.len();
-        for p in self.player_out.values() {
-            p.send_message(StateUpdate::LobbyCount(connected as u8));
+        let connected = self.player_out.len() + self.bots.len();
+        self.broadcast(StateUpdate::LobbyCount(connected.min(u16::MAX as usize) as u16));
+    }
+
+    fn send_to(&mut self, player: PlayerId, update: StateUpdate) {
+        self.outbox.push(Outgoing::To(player, update));
+    }
+
+    // Goes to connected players in id order so stepped runs come out the
+    // same every time
+    fn broadcast(&mut self, update: StateUpdate) {
+        let ids = self.connected_ids();
+        self.outbox.push(Outgoing::Broadcast(ids, update));
+    }
+
+    fn connected_ids(&self) -> Vec<PlayerId> {
+        let mut ids: Vec<PlayerId> = self.player_out.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn flush(&mut self) {
+        let mut lagging = Vec::new();
+        for out in self.outbox.drain(..) {
+            match out {
+                Outgoing::To(id, update) => {
+                    if let Some(p) = self.player_out.get(&id) {
+                        let frame = Arc::new(protocol::encode(&update, p.encoding()));
+                        if let StateUpdate::GameState(_) = update {
+                            METRICS.set_state_size(frame.len());
+                        }
+                        if let Err(e) = p.send_frame(update.snapshot_kind(), frame) {
+                            lagging.push((id, e));
+                        }
+                    }
+                }
+                Outgoing::Broadcast(ids, update) => {
+                    // Encoded once for each encoding in use, and the bytes
+                    // shared between everyone using it
+                    let snapshot = update.snapshot_kind();
+                    let mut frames: Vec<(Encoding, Frame)> = Vec::new();
+                    for id in ids {
+                        let p = match self.player_out.get(&id) {
+                            Some(p) => p,
+                            None => continue,
+                        };
+                        let existing = frames.iter().find(|&&(e, _)| e == p.encoding()).map(|(_, f)| f.clone());
+                        let frame = match existing {
+                            Some(frame) => frame,
+                            None => {
+                                let frame = Arc::new(protocol::encode(&update, p.encoding()));
+                                if let StateUpdate::GameState(_) = update {
+                                    METRICS.set_state_size(frame.len());
+                                }
+                                frames.push((p.encoding(), frame.clone()));
+                                frame
+                            }
+                        };
+                        if let Err(e) = p.send_frame(snapshot, frame) {
+                            lagging.push((id, e));
+                        }
+                    }
+                }
+            }
+        }
+        for (id, reason) in lagging {
+            if self.player_out.contains_key(&id) {
+                warn!(room = self.room, player = id, tick = self.model.tick, reason = reason.as_str(); "Disconnecting lagging player");
+                self.handle_event(InputEvent::Disconnection(id));
+            }
         }
     }
+
+    pub fn lag_stats(&self) -> Vec<(PlayerId, LagStats)> {
+        let mut stats: Vec<(PlayerId, LagStats)> = self.player_out.iter()
+            .filter_map(|(id, p)| p.lag_stats().map(|s| (*id, s)))
+            .collect();
+        stats.sort_by_key(|&(id, _)| id);
+        stats
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::mpsc::{ channel, Sender, Receiver };
-    use std::thread;
+    use std::sync::mpsc::channel;
+    use ticks::NoWaitTicker;
     use std::time::Duration;
-    use ticks::TestTicker;
+    use placement::StationPlacement;
+    use out_queue::{ OutQueue, QueueLimits };
     use player::Player;
     use player_id::PlayerId;
     use events::*;
     use super::*;
 
-    fn connect_player(sender: &Sender<InputEvent>, id: u16) -> Receiver<StateUpdate> {
-        let (ps, pr) = channel();
-        let p_id = PlayerId::new(id);
-        let p = Player::new(ps);
-        sender.send(InputEvent::Connection(p_id, p)).expect("test player connect");
-        pr
+    fn test_game() -> MetroGame<NoWaitTicker, Always1Random> {
+        let (_, gr) = channel();
+        MetroGame::new(gr, NoWaitTicker { tick_length: 1. }, Always1Random)
     }
 
-    fn disconnect_player(sender: &Sender<InputEvent>, id: u16) {
-        sender.send(InputEvent::Disconnection(PlayerId::new(id))).expect("test pkayer disconnect");
+    fn connect_player(id: u16) -> InputEvent {
+        InputEvent::Connection(PlayerId::new(id), Player::detached())
     }
 
-    fn start_test_game() -> (Sender<InputEvent>, (Sender<f64>, Receiver<()>)) {
-        let (tsw, trw) = channel();
-        let (tss, trs) = channel();
-        let t = TestTicker { r: trw, s: tss };
-        let (gs, gr) = channel();
-        let mut test_game = MetroGame::new(gr, t, Always1Random);
-        thread::spawn(move || test_game.main());
-        thread::sleep(Duration::from_millis(200));
-        (gs, (tsw, trs))
+    fn disconnect_player(id: u16) -> InputEvent {
+        InputEvent::Disconnection(PlayerId::new(id))
     }
 
-    fn send_player_action(sender: &Sender<InputEvent>, id: u16, action: PlayerAction) {
-        sender.send(InputEvent::PlayerAction(PlayerId::new(id), action))
-            .expect("Test sending player action");
+    fn player_action(id: u16, action: PlayerAction) -> InputEvent {
+        InputEvent::PlayerAction(PlayerId::new(id), action)
     }
 
-    fn tick(&(ref tsw, ref trs): &(Sender<f64>, Receiver<()>)) {
-        tsw.send(1f64).unwrap();
-        println!("waiting tick end");
-        trs.recv().unwrap();
-        println!("tick end");
+    // What one player was sent during a step, in order
+    fn updates_for(updates: &[(PlayerId, StateUpdate)], id: u16) -> Vec<StateUpdate> {
+        updates.iter()
+            .filter(|&(p, _)| *p == PlayerId::new(id))
+            .map(|(_, u)| u.clone())
+            .collect()
+    }
+
+    fn destinations(passengers: &[Passenger]) -> Vec<StationType> {
+        passengers.iter().map(|p| p.destination.clone()).collect()
     }
 
     #[test]
     fn connecting_players() {
-        let (gs, ticks) = start_test_game();
-        let pr1 = connect_player(&gs, 1);
-        tick(&ticks);
-        assert_eq!(Ok(StateUpdate::You(PlayerId::new(1))), pr1.recv());
-        assert_eq!(Ok(StateUpdate::LobbyCount(1)), pr1.recv());
-        let pr2 = connect_player(&gs, 2);
-        tick(&ticks);
-        assert_eq!(Ok(StateUpdate::LobbyCount(2)), pr1.recv());
-        assert_eq!(Ok(StateUpdate::You(PlayerId::new(2))), pr2.recv());
-        assert_eq!(Ok(StateUpdate::LobbyCount(2)), pr2.recv());
-        disconnect_player(&gs, 1);
-        tick(&ticks);
-        assert!(pr1.try_recv().is_err());
-        assert_eq!(Ok(StateUpdate::LobbyCount(1)), pr2.recv());
+        let mut game = test_game();
+        let updates = game.step(vec![ connect_player(1) ]);
+        let p1 = updates_for(&updates, 1);
+        assert_eq!(3, p1.len());
+        assert_eq!(StateUpdate::You(PlayerId::new(1)), p1[0]);
+        assert_is_lobby_settings(&p1[1], "Classic", 1);
+        assert_eq!(StateUpdate::LobbyCount(1), p1[2]);
+
+        let updates = game.step(vec![ connect_player(2) ]);
+        assert_eq!(vec![ StateUpdate::LobbyCount(2) ], updates_for(&updates, 1));
+        let p2 = updates_for(&updates, 2);
+        assert_eq!(3, p2.len());
+        assert_eq!(StateUpdate::You(PlayerId::new(2)), p2[0]);
+        assert_is_lobby_settings(&p2[1], "Classic", 1);
+        assert_eq!(StateUpdate::LobbyCount(2), p2[2]);
+
+        let updates = game.step(vec![ disconnect_player(1) ]);
+        assert!(updates_for(&updates, 1).is_empty());
+        let p2 = updates_for(&updates, 2);
+        assert_eq!(2, p2.len());
+        assert_is_lobby_settings(&p2[0], "Classic", 2);
+        assert_eq!(StateUpdate::LobbyCount(1), p2[1]);
+    }
+
+    #[test]
+    fn output_goes_to_player_channels() {
+        let (gs, gr) = channel();
+        let mut game = MetroGame::new(gr, NoWaitTicker { tick_length: 1. }, Always1Random);
+        let q1 = OutQueue::new(QueueLimits::default());
+        let q2 = OutQueue::new(QueueLimits::default());
+        let q3 = OutQueue::new(QueueLimits::default());
+        gs.send(InputEvent::Connection(PlayerId::new(1), Player::new(q1.clone(), Encoding::Json))).unwrap();
+        gs.send(InputEvent::Connection(PlayerId::new(2), Player::new(q2.clone(), Encoding::Json))).unwrap();
+        gs.send(InputEvent::Connection(PlayerId::new(3), Player::new(q3.clone(), Encoding::MessagePack))).unwrap();
+        game.input();
+        game.update();
+        game.output();
+        assert_eq!(b"{\"You\":1}".to_vec(), *q1.try_pop().unwrap());
+        assert!(String::from_utf8(q1.try_pop().unwrap().to_vec()).unwrap().starts_with("{\"LobbySettings\":"));
+        let count1 = q1.try_pop().unwrap();
+        assert_eq!(b"{\"LobbyCount\":3}".to_vec(), *count1);
+        assert!(q1.try_pop().is_none());
+
+        // The broadcast is encoded once per encoding and shared
+        let count2 = q2.pop().and(q2.pop()).and(q2.pop()).unwrap();
+        let count3 = q3.pop().and(q3.pop()).and(q3.pop()).unwrap();
+        assert!(Arc::ptr_eq(&count1, &count2));
+        assert_eq!(protocol::encode(&StateUpdate::LobbyCount(3), Encoding::MessagePack), *count3);
+    }
+
+    #[test]
+    fn lagging_players_are_dropped() {
+        let mut game = test_game();
+        let slow = OutQueue::new(QueueLimits { capacity: 1, max_lag: Duration::from_secs(60) });
+        game.step(vec![
+            InputEvent::Connection(PlayerId::new(1), Player::new(OutQueue::new(QueueLimits::default()), Encoding::Json)),
+            InputEvent::Connection(PlayerId::new(2), Player::new(slow.clone(), Encoding::Json)),
+        ]);
+        // Nothing is read from either queue, but repeated lobby counts
+        // replace each other so there's only ever one waiting
+        for _ in 0..3 {
+            game.output();
+        }
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(2)], game.connected_ids());
+        let stats = game.lag_stats();
+        assert_eq!(1, stats[1].1.queued);
+        assert_eq!(2, stats[1].1.coalesced);
+
+        // A game state on top of that is more than the slow player can hold
+        game.step(vec![ player_action(1, PlayerAction::StartGame) ]);
+        game.output();
+        assert_eq!(vec![PlayerId::new(1)], game.connected_ids());
+        assert_eq!(None, slow.pop());
+    }
+
+    #[test]
+    fn round_trips_show_in_game_state() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            InputEvent::RoundTrip(PlayerId::new(1), Duration::from_millis(42)),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        let updates = game.step(vec![
+            InputEvent::RoundTrip(PlayerId::new(2), Duration::from_millis(1500)),
+            disconnect_player(1),
+        ]);
+        match updates_for(&updates, 2)[0] {
+            StateUpdate::GameState(ref state) => {
+                assert_eq!(None, state.round_trips.get(&PlayerId::new(1)));
+                assert_eq!(Some(&1500), state.round_trips.get(&PlayerId::new(2)));
+            }
+            ref update => panic!("{:?} is not a GameState", update),
+        }
+        assert_eq!(1, game.model().round_trips().len());
+    }
+
+    fn assert_is_lobby_settings(update: &StateUpdate, expected_map: &str, expected_host: u16) {
+        match *update {
+            StateUpdate::LobbySettings { ref maps, ref map, ref host, .. } => {
+                assert!(maps.iter().any(|m| m == expected_map));
+                assert_eq!(expected_map, map);
+                assert_eq!(&Some(PlayerId::new(expected_host)), host);
+            }
+            _ => {
+                panic!("{:?} is not a LobbySettings", update);
+            }
+        }
+    }
+
+    #[test]
+    fn host_selects_map() {
+        let mut game = test_game();
+        let updates = game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            player_action(2, PlayerAction::SelectMap("Riverside".to_string())),
+            player_action(1, PlayerAction::SelectMap("Lakeland".to_string())),
+            player_action(1, PlayerAction::SelectMap("Nowhere".to_string())),
+        ]);
+        let p1 = updates_for(&updates, 1);
+        assert_eq!(StateUpdate::You(PlayerId::new(1)), p1[0]);
+        assert_is_lobby_settings(&p1[1], "Classic", 1);
+        assert_is_lobby_settings(&p1[2], "Lakeland", 1);
+        assert_eq!(StateUpdate::LobbyCount(2), p1[3]);
+        let p2 = updates_for(&updates, 2);
+        assert_eq!(StateUpdate::You(PlayerId::new(2)), p2[0]);
+        assert_is_lobby_settings(&p2[1], "Classic", 1);
+        assert_is_lobby_settings(&p2[2], "Lakeland", 1);
+
+        let updates = game.step(vec![ player_action(1, PlayerAction::StartGame) ]);
+        match updates_for(&updates, 2)[0] {
+            StateUpdate::GameState(ref state) => {
+                assert_eq!(4, state.terrain.len());
+                assert_eq!((-150., 60.), state.stations[1].position);
+                assert_eq!(2, state.lines.len());
+            }
+            ref update => panic!("{:?} is not a GameState", update),
+        }
+    }
+
+    #[test]
+    fn players_only_see_their_own_scores() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            player_action(2, PlayerAction::SelectVisibility("FogOfWar".to_string())),
+            player_action(1, PlayerAction::SelectVisibility("HiddenScores".to_string())),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        game.model.add_score(PlayerId::new(1), 3.);
+        game.model.add_score(PlayerId::new(2), 5.);
+        let updates = game.step(Vec::new());
+        for &(id, score) in [(1, 3.), (2, 5.)].iter() {
+            match updates_for(&updates, id)[0] {
+                StateUpdate::GameState(ref state) => {
+                    assert_eq!(1, state.scores.len());
+                    assert_eq!(Some(&score), state.scores.get(&PlayerId::new(id)));
+                }
+                ref update => panic!("{:?} is not a GameState", update),
+            }
+        }
+        // Nor can anyone outside the game
+        assert_eq!(None, game.room_summary().scores);
+        assert!(game.public_state().is_none());
+    }
+
+    #[test]
+    fn overcrowding_ends_the_game() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            player_action(1, PlayerAction::SetName(" ada ".to_string())),
+            player_action(2, PlayerAction::SetName("x".repeat(MAX_NAME_LENGTH + 1))),
+            player_action(1, PlayerAction::SelectVisibility("HiddenScores".to_string())),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        game.model.add_score(PlayerId::new(2), 5.);
+        // Stations without a crowd cool down a tick before anyone checks
+        game.model.stations[1].blow_time = game.model.time_to_blow + 1;
+        let updates = game.step(Vec::new());
+
+        let result = match updates_for(&updates, 1)[0] {
+            StateUpdate::GameOver(ref result) => result.clone(),
+            ref update => panic!("{:?} is not a GameOver", update),
+        };
+        assert_eq!(GameEnd::Overcrowded(StationId(1)), result.ended);
+        // Hidden scores come out once it's over
+        let scores: Vec<(&str, f32)> = result.players.iter().map(|p| (p.name.as_str(), p.score)).collect();
+        assert_eq!(vec![("ada", 0.), ("Player 2", 5.)], scores);
+        assert_eq!("lobby", game.room_summary().phase);
+        assert_eq!(Some(result), game.take_result());
+        assert_eq!(None, game.take_result());
+    }
+
+    #[test]
+    fn everyone_leaving_ends_the_game() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            player_action(1, PlayerAction::AddBot("Greedy".to_string())),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        game.step(Vec::new());
+        assert_eq!(None, game.take_result());
+
+        game.step(vec![disconnect_player(1)]);
+        let result = game.take_result().unwrap();
+        assert_eq!(GameEnd::Abandoned, result.ended);
+        let players: Vec<(&str, bool)> = result.players.iter().map(|p| (p.name.as_str(), p.bot)).collect();
+        assert_eq!(vec![("Player 1", false), ("Greedy", true)], players);
+    }
+
+    #[test]
+    fn chat_reaches_everyone_and_late_joiners() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1), connect_player(2)]);
+        let tick = game.model.tick();
+        let updates = game.step(vec![
+            player_action(1, PlayerAction::Chat("  hello\u{7} ".to_string())),
+            player_action(2, PlayerAction::Chat("x".repeat(MAX_CHAT_LENGTH + 1))),
+            player_action(2, PlayerAction::Emote(Emote::Wave)),
+        ]);
+        let hello = StateUpdate::Chat { from: PlayerId::new(1), text: "hello".to_string(), tick: tick };
+        let wave = StateUpdate::Chat { from: PlayerId::new(2), text: Emote::Wave.text().to_string(), tick: tick };
+        assert_eq!(vec![hello.clone(), wave.clone()], updates_for(&updates, 1)[..2].to_vec());
+        match updates_for(&updates, 2)[1] {
+            StateUpdate::Warning(ref w) => assert!(w.contains("at most")),
+            ref update => panic!("{:?} is not a Warning", update),
+        }
+
+        // Chat carries on into the game, where newcomers catch up on it
+        let updates = game.step(vec![
+            player_action(1, PlayerAction::StartGame),
+            connect_player(3),
+        ]);
+        assert_eq!(vec![StateUpdate::You(PlayerId::new(3)), hello, wave], updates_for(&updates, 3)[..3].to_vec());
+    }
+
+    #[test]
+    fn players_only_extend_their_own_lines() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1), connect_player(2), player_action(1, PlayerAction::StartGame)]);
+        let a = game.model.add_station(StationType::Circle, (0., 0.));
+        let b = game.model.add_station(StationType::Square, (50., 0.));
+        let c = game.model.add_station(StationType::Triangle, (100., 0.));
+        game.step(vec![player_action(1, PlayerAction::NewLine(a.clone(), b.clone()))]);
+        assert_eq!(PlayerId::new(1), game.model.lines[0].owning_player);
+        game.step(vec![
+            player_action(2, PlayerAction::InsertAtLineEnd(LineId(0), c.clone())),
+            player_action(2, PlayerAction::InsertAtLineBeginning(LineId(0), c.clone())),
+        ]);
+        assert_eq!(vec![&a, &b], game.model.lines[0].all_stations());
+        game.step(vec![player_action(1, PlayerAction::InsertAtLineEnd(LineId(0), c.clone()))]);
+        assert_eq!(vec![&a, &b, &c], game.model.lines[0].all_stations());
+    }
+
+    #[test]
+    fn teams_share_lines_and_chat() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            connect_player(3),
+            player_action(2, PlayerAction::SetTeamCount(2)),
+            player_action(1, PlayerAction::SetTeamCount(5)),
+            player_action(1, PlayerAction::SetTeamCount(2)),
+        ]);
+        assert_eq!(Some(TeamId(0)), game.model.team_of(&PlayerId::new(3)));
+        game.step(vec![player_action(3, PlayerAction::JoinTeam(TeamId(1)))]);
+        game.step(vec![player_action(2, PlayerAction::JoinTeam(TeamId(0))), player_action(1, PlayerAction::StartGame)]);
+        let teams: Vec<Option<TeamId>> = (1..4).map(|i| game.model.team_of(&PlayerId::new(i))).collect();
+        assert_eq!(vec![Some(TeamId(0)), Some(TeamId(0)), Some(TeamId(1))], teams);
+
+        // One line per team, which any member can build on
+        let owners: Vec<PlayerId> = game.model.lines.iter().map(|l| l.owning_player).collect();
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(3)], owners);
+        let a = game.model.add_station(StationType::Circle, (0., 0.));
+        let b = game.model.add_station(StationType::Square, (50., 0.));
+        let c = game.model.add_station(StationType::Triangle, (100., 0.));
+        game.step(vec![
+            player_action(2, PlayerAction::NewLine(a.clone(), b.clone())),
+            player_action(3, PlayerAction::InsertAtLineEnd(LineId(0), c.clone())),
+        ]);
+        assert_eq!(vec![&a, &b], game.model.lines[0].all_stations());
+        game.step(vec![player_action(1, PlayerAction::InsertAtLineEnd(LineId(0), c.clone()))]);
+        assert_eq!(vec![&a, &b, &c], game.model.lines[0].all_stations());
+
+        // The new week's line goes to whoever on the team has fewest
+        game.finish_week();
+        let owners: Vec<PlayerId> = game.model.lines.iter().map(|l| l.owning_player).collect();
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(3), PlayerId::new(2), PlayerId::new(3)], owners);
+
+        game.model.add_score(PlayerId::new(1), 2.);
+        game.model.add_score(PlayerId::new(2), 3.);
+        assert_eq!(Some(&5.), game.model.team_scores().get(&TeamId(0)));
+        assert_eq!(Some(&5.), game.room_summary().team_scores.as_ref().and_then(|s| s.get(&TeamId(0))));
+
+        let updates = game.step(vec![player_action(2, PlayerAction::Chat("left side".to_string()))]);
+        let heard: Vec<PlayerId> = updates.iter()
+            .filter(|&(_, u)| matches!(u, StateUpdate::Chat { .. }))
+            .map(|&(id, _)| id)
+            .collect();
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(2)], heard);
+    }
+
+    // Brings in a station every tick in place of the usual spawning
+    struct StationEveryTick;
+
+    impl Ruleset for StationEveryTick {
+        fn name(&self) -> &'static str {
+            "StationEveryTick"
+        }
+
+        fn on_tick(&mut self, model: &mut MetroModel, _spawner: &mut Spawner, _random: &dyn Random) {
+            model.add_station(StationType::Circle, (0., 0.));
+        }
+
+        fn on_delivery(&mut self, _model: &mut MetroModel, _delivery: &Delivery, _scoring: &dyn ScoringPolicy) {}
+
+        fn validate_action(&self, _model: &MetroModel, _player: &PlayerId, _action: &PlayerAction) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn rulesets_can_take_over_spawning() {
+        let mut game = test_game();
+        game.ruleset = Box::new(StationEveryTick);
+        game.step(vec![connect_player(1)]);
+        game.step(vec![player_action(1, PlayerAction::StartGame)]);
+        let started = game.model.station_count();
+        game.step(vec![]);
+        game.step(vec![]);
+        assert_eq!(started + 2, game.model.station_count());
+    }
+
+    #[test]
+    fn coop_shares_one_network() {
+        let mut game = test_game();
+        game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            player_action(2, PlayerAction::SelectRuleset("Coop".to_string())),
+        ]);
+        assert_eq!("Competitive", game.ruleset.name());
+        game.step(vec![
+            player_action(1, PlayerAction::SelectRuleset("Coop".to_string())),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        assert_eq!("Coop", game.ruleset.name());
+        assert_eq!(1, game.model.lines.len());
+
+        // Anyone can build on any line
+        let a = game.model.add_station(StationType::Circle, (0., 0.));
+        let b = game.model.add_station(StationType::Square, (50., 0.));
+        let c = game.model.add_station(StationType::Triangle, (100., 0.));
+        game.step(vec![
+            player_action(2, PlayerAction::NewLine(a.clone(), b.clone())),
+            player_action(2, PlayerAction::InsertAtLineEnd(LineId(0), c.clone())),
+        ]);
+        assert_eq!(vec![&a, &b, &c], game.model.lines[0].all_stations());
+
+        // The line cap is for everyone together
+        game.max_lines_per_player = 2;
+        game.finish_week();
+        game.finish_week();
+        let owners: Vec<PlayerId> = game.model.lines.iter().map(|l| l.owning_player).collect();
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(2)], owners);
+
+        // Everyone's score is everything delivered, so everyone wins together
+        game.model.delivery_stats.entry(PlayerId::new(1)).or_default().record(10, 0);
+        game.model.delivery_stats.entry(PlayerId::new(2)).or_default().record(10, 0);
+        let result = game.result(GameEnd::Overcrowded(a));
+        let scores: Vec<f32> = result.players.iter().map(|p| p.score).collect();
+        assert_eq!(vec![2., 2.], scores);
+        assert_eq!(2, result.winners().len());
+    }
+
+    #[test]
+    fn chat_is_rate_limited() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1)]);
+        let chats = (0..CHAT_BURST as usize + 1).map(|i| player_action(1, PlayerAction::Chat(i.to_string()))).collect();
+        let updates = game.step(chats);
+        let warnings: Vec<StateUpdate> = updates_for(&updates, 1).into_iter()
+            .filter(|u| matches!(*u, StateUpdate::Warning(_)))
+            .collect();
+        assert_eq!(vec![StateUpdate::Warning("You're chatting too fast".to_string())], warnings);
+        assert_eq!(CHAT_BURST as usize, game.chat_history.len());
+        // Allowance comes back with game ticks, however quickly they pass.
+        // The burst went a tick ago, so one short of a message is left.
+        for _ in 0..CHAT_TICKS_PER_MESSAGE - 2 {
+            game.step(vec![]);
+        }
+        game.step(vec![player_action(1, PlayerAction::Chat("again".to_string()))]);
+        assert_eq!(CHAT_BURST as usize, game.chat_history.len());
+        game.step(vec![player_action(1, PlayerAction::Chat("again".to_string()))]);
+        assert_eq!(CHAT_BURST as usize + 1, game.chat_history.len());
+    }
+
+    #[test]
+    fn bots_are_capped() {
+        let mut game = test_game();
+        let mut events = vec![connect_player(1)];
+        for _ in 0..MAX_BOTS + 1 {
+            events.push(player_action(1, PlayerAction::AddBot("Greedy".to_string())));
+        }
+        let updates = game.step(events);
+        assert_eq!(MAX_BOTS, game.bots.len());
+        assert!(game.bots.iter().all(|&(id, _)| id > PlayerId::new(MAX_CONNECTION_ID)));
+        assert!(updates_for(&updates, 1).contains(&StateUpdate::Warning(format!("A game can have at most {} bots", MAX_BOTS))));
+        assert_eq!(Some(&StateUpdate::LobbyCount(MAX_BOTS as u16 + 1)), updates_for(&updates, 1).last());
+    }
+
+    #[test]
+    fn room_summary() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1), player_action(1, PlayerAction::AddBot("Greedy".to_string()))]);
+        let summary = game.room_summary();
+        assert_eq!("lobby", summary.phase);
+        assert_eq!((1, 1), (summary.players, summary.bots));
+        assert!(game.public_state().is_none());
+
+        game.step(vec![player_action(1, PlayerAction::StartGame)]);
+        game.model.add_score(PlayerId::new(1), 2.);
+        let summary = game.room_summary();
+        assert_eq!("game", summary.phase);
+        assert_eq!(Some(&2.), summary.scores.as_ref().and_then(|s| s.get(&PlayerId::new(1))));
+        assert_eq!(Some(game.model.tick()), game.public_state().map(|m| m.tick()));
+    }
+
+    #[test]
+    fn fog_of_war_view() {
+        let me = PlayerId::new(1);
+        let other = PlayerId::new(2);
+        let mut m = MetroModel::new();
+        m.add_station(StationType::Circle, (0., 0.));
+        m.add_station(StationType::Square, (100., 0.));
+        m.add_station(StationType::Triangle, (400., 0.));
+        m.add_station(StationType::Circle, (500., 0.));
+        for s in m.stations.iter_mut() {
+            s.passengers.push(Passenger::new(StationType::Circle, StationId(0), 0));
+        }
+        m.add_line(me, (0., 0., 0.));
+        m.add_line(other, (0., 0., 0.));
+        m.start_new_line(&me, &StationId(0), &StationId(1));
+        m.add_train_to_line(&LineId(0));
+        m.start_new_line(&other, &StationId(2), &StationId(3));
+        m.add_train_to_line(&LineId(1));
+        m.insert_before_line(&LineId(1), &StationId(1));
+        m.add_score(other, 2.);
+
+        let view = m.view_for(&me, &Visibility::FogOfWar { radius: 150. });
+        assert_eq!(4, view.stations.len());
+        assert_eq!(vec![1, 1, 0, 0], view.stations.iter().map(|s| s.passenger_count()).collect::<Vec<_>>());
+        // Only the other line's edge that reaches into sight is left
+        assert_eq!(2, view.lines.len());
+        assert_eq!(1, view.lines[1].edges.len());
+        assert_eq!(StationId(1), view.lines[1].edges[0].origin);
+        assert_eq!(vec![LineId(0)], view.trains.iter().map(|t| t.on_line.clone()).collect::<Vec<_>>());
+        assert_eq!(m.scores, view.scores);
+
+        assert_eq!(m, m.view_for(&me, &Visibility::Full));
+        assert!(m.view_for(&me, &Visibility::HiddenScores).scores.is_empty());
+    }
+
+    #[test]
+    fn unknown_stations_are_ignored() {
+        let mut game = test_game();
+        game.visibility = Visibility::FogOfWar { radius: 150. };
+        game.step(vec![connect_player(1), connect_player(2), player_action(1, PlayerAction::StartGame)]);
+        let missing = StationId(game.model.station_count());
+        game.step(vec![
+            player_action(1, PlayerAction::NewLine(StationId(0), missing.clone())),
+            player_action(2, PlayerAction::NewLine(StationId(0), StationId(1))),
+        ]);
+        game.step(vec![
+            player_action(2, PlayerAction::InsertAtLineBeginning(LineId(1), missing.clone())),
+            player_action(2, PlayerAction::InsertAtLineEnd(LineId(1), missing.clone())),
+        ]);
+        assert!(game.model.lines[0].edges.is_empty());
+        assert_eq!(vec![&StationId(0), &StationId(1)], game.model.lines[1].all_stations());
+        // Still running, fog and all
+        game.step(vec![]);
+        assert_eq!(MGameState::Game, game.state);
+    }
+
+    #[test]
+    fn finished_games_stand_still() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1), player_action(1, PlayerAction::StartGame)]);
+        game.ticks_per_week = 2;
+        game.step(vec![player_action(1, PlayerAction::NewLine(StationId(0), StationId(1)))]);
+        game.model.add_score(PlayerId::new(1), 3.);
+        game.end_game(GameEnd::Abandoned);
+        let ended = game.model.clone();
+        for _ in 0..10 {
+            game.step(vec![]);
+        }
+        assert_eq!(ended, game.model);
+        assert_eq!(Some(&3.), game.model.scores().get(&PlayerId::new(1)));
+    }
+
+    #[test]
+    fn every_game_has_a_seed_of_its_own() {
+        let (_, gr) = channel();
+        let mut game = MetroGame::new(gr, NoWaitTicker { tick_length: 1. }, SeededRandom::new(7));
+        let mut seeds = Vec::new();
+        for _ in 0..2 {
+            game.step(vec![connect_player(1), player_action(1, PlayerAction::StartGame)]);
+            let first_roll = game.random.gen();
+            let mut replay = SeededRandom::new(game.random.seed().unwrap());
+            replay.reseed();
+            assert_eq!(replay.gen(), first_roll);
+            game.end_game(GameEnd::Abandoned);
+            seeds.push(game.take_result().and_then(|r| r.seed));
+        }
+        assert_eq!(Some(7), seeds[0]);
+        assert!(seeds[1].is_some() && seeds[1] != seeds[0]);
+    }
+
+    #[test]
+    fn bots_get_past_unknown_stations() {
+        let mut game = test_game();
+        game.step(vec![connect_player(1), player_action(1, PlayerAction::AddBot("Greedy".to_string()))]);
+        game.step(vec![player_action(1, PlayerAction::StartGame)]);
+        let missing = StationId(game.model.station_count());
+        game.model.lines[0].edges.push(Edge { origin: StationId(0), destination: missing, via_point: (0., 0.) });
+        // Long enough for the bot to think again
+        for _ in 0..60 {
+            game.step(vec![]);
+        }
+        assert_eq!(MGameState::Game, game.state);
     }
 
     fn assert_is_game_start(update: &StateUpdate) {
@@ -1050,37 +2608,32 @@ mod tests {
 
     #[test]
     fn game_progression() {
-        let (gs, ticks) = start_test_game();
-        let pr1 = connect_player(&gs, 1);
-        let pr2 = connect_player(&gs, 2);
-        send_player_action(&gs, 1, PlayerAction::StartGame);
-        tick(&ticks);
-        assert_eq!(Ok(StateUpdate::You(PlayerId::new(1))), pr1.recv());
-        assert_eq!(Ok(StateUpdate::You(PlayerId::new(2))), pr2.recv());
-        assert_is_game_start(&pr1.recv().unwrap());
-        assert_is_game_start(&pr2.recv().unwrap());
+        let mut game = test_game();
+        let updates = game.step(vec![
+            connect_player(1),
+            connect_player(2),
+            player_action(1, PlayerAction::StartGame),
+        ]);
+        for id in 1..3 {
+            let p = updates_for(&updates, id);
+            assert_eq!(StateUpdate::You(PlayerId::new(id)), p[0]);
+            assert_is_lobby_settings(&p[1], "Classic", 1);
+            assert_is_game_start(&p[2]);
+        }
         let attempt_src = StationId(0);
         let attempt_tgt = StationId(1);
-        send_player_action(&gs, 1, PlayerAction::NewLine(attempt_src.clone(), attempt_tgt.clone()));
-        tick(&ticks);
-        assert_has_edge(&pr1.recv().unwrap(), &attempt_src, &attempt_tgt, Some((-45., 25.)));
-        assert_has_edge(&pr2.recv().unwrap(), &attempt_src, &attempt_tgt, Some((-45., 25.)));
+        let updates = game.step(vec![ player_action(1, PlayerAction::NewLine(attempt_src.clone(), attempt_tgt.clone())) ]);
+        assert_has_edge(&updates_for(&updates, 1)[0], &attempt_src, &attempt_tgt, Some((-45., 25.)));
+        assert_has_edge(&updates_for(&updates, 2)[0], &attempt_src, &attempt_tgt, Some((-45., 25.)));
     }
 
     #[test]
     fn insert_before_line() {
-        let (gs, ticks) = start_test_game();
-        let pr1 = connect_player(&gs, 1);
-        send_player_action(&gs, 1, PlayerAction::StartGame);
-        tick(&ticks);
-        assert_eq!(Ok(StateUpdate::You(PlayerId::new(1))), pr1.recv());
-        pr1.recv().unwrap();
-        send_player_action(&gs, 1, PlayerAction::NewLine(StationId(0), StationId(1)));
-        tick(&ticks);
-        pr1.recv().unwrap();
-        send_player_action(&gs, 1, PlayerAction::InsertAtLineBeginning(LineId(0), StationId(2)));
-        tick(&ticks);
-        assert_has_edge(&pr1.recv().unwrap(), &StationId(2), &StationId(0), None);
+        let mut game = test_game();
+        game.step(vec![ connect_player(1), player_action(1, PlayerAction::StartGame) ]);
+        game.step(vec![ player_action(1, PlayerAction::NewLine(StationId(0), StationId(1))) ]);
+        let updates = game.step(vec![ player_action(1, PlayerAction::InsertAtLineBeginning(LineId(0), StationId(2))) ]);
+        assert_has_edge(&updates_for(&updates, 1)[0], &StationId(2), &StationId(0), None);
     }
 
     #[test]
@@ -1448,10 +3001,10 @@ mod tests {
         m.stations.push(test_loc2);
         m.lines.push(Line { edges: vec![ test_edge1 ], colour: (0., 0., 0.), owning_player: player });
         let mut train = Train::new(LineId(0), (0., 0.), (10., 10.), true, StationId(0), StationId(1), 10.);
-        train.passengers.push(StationType::Circle);
-        train.passengers.push(StationType::Triangle);
-        train.passengers.push(StationType::Square);
-        train.passengers.push(StationType::Circle);
+        train.passengers.push(Passenger::new(StationType::Circle, StationId(0), 0));
+        train.passengers.push(Passenger::new(StationType::Triangle, StationId(0), 0));
+        train.passengers.push(Passenger::new(StationType::Square, StationId(0), 0));
+        train.passengers.push(Passenger::new(StationType::Circle, StationId(0), 0));
         m.trains.push(train);
 
         m.update();
@@ -1464,11 +3017,11 @@ mod tests {
 
         for _ in 0..30 {
             assert_eq!((10., 20.), m.trains[0].position);
-            assert_eq!(vec![StationType::Circle, StationType::Triangle, StationType::Square, StationType::Circle], m.trains[0].passengers);
+            assert_eq!(vec![StationType::Circle, StationType::Triangle, StationType::Square, StationType::Circle], destinations(&m.trains[0].passengers));
             m.update();
         }
         assert_eq!((10., 20.), m.trains[0].position);
-        assert_eq!(vec![StationType::Circle, StationType::Square, StationType::Circle], m.trains[0].passengers);
+        assert_eq!(vec![StationType::Circle, StationType::Square, StationType::Circle], destinations(&m.trains[0].passengers));
 
         m.update();
         assert_eq!((10., 10.), m.trains[0].position);
@@ -1478,21 +3031,61 @@ mod tests {
 
         for _ in 0..30 {
             assert_eq!((0., 0.), m.trains[0].position);
-            assert_eq!(vec![StationType::Circle, StationType::Square, StationType::Circle], m.trains[0].passengers);
+            assert_eq!(vec![StationType::Circle, StationType::Square, StationType::Circle], destinations(&m.trains[0].passengers));
             m.update();
         }
         for _ in 0..30 {
             assert_eq!((0., 0.), m.trains[0].position);
-            assert_eq!(vec![StationType::Square, StationType::Circle], m.trains[0].passengers);
+            assert_eq!(vec![StationType::Square, StationType::Circle], destinations(&m.trains[0].passengers));
             m.update();
         }
         assert_eq!((0., 0.), m.trains[0].position);
-        assert_eq!(vec![StationType::Square], m.trains[0].passengers);
+        assert_eq!(vec![StationType::Square], destinations(&m.trains[0].passengers));
 
         m.update();
         assert_eq!((10., 10.), m.trains[0].position);
     }
 
+    #[test]
+    pub fn delivery_tracking() {
+        let player = PlayerId::new(3);
+        let mut m = MetroModel::new();
+        m.delivery_scoring = DeliveryScoring::Speed { target_ticks: 100, max_bonus: 4 };
+        m.stations.push(Station::new(StationType::Circle, (0., 0.)));
+        m.stations.push(Station::new(StationType::Triangle, (10., 20.)));
+        m.lines.push(Line {
+            edges: vec![ Edge { origin: StationId(0), destination: StationId(1), via_point: (10., 10.) } ],
+            colour: (0., 0., 0.),
+            owning_player: player,
+        });
+        let mut train = Train::new(LineId(0), (0., 0.), (10., 10.), true, StationId(0), StationId(1), 10.);
+        let mut passenger = Passenger::new(StationType::Triangle, StationId(0), 0);
+        passenger.transfers = 1;
+        passenger.legs.push(JourneyLeg { line: LineId(0), player: player, distance: 0. });
+        train.passengers.push(passenger);
+        m.trains.push(train);
+
+        // Two ticks to arrive, then the usual wait before alighting
+        for _ in 0..32 {
+            m.update();
+        }
+        assert!(m.trains[0].passengers.is_empty());
+        let deliveries = m.take_deliveries();
+        assert_eq!(1, deliveries.len());
+        assert_eq!(31, deliveries[0].journey_ticks);
+        assert_eq!(4., deliveries[0].points);
+        let leg = &deliveries[0].passenger.legs()[0];
+        assert_eq!(player, leg.player);
+        assert!((leg.distance - (200f32.sqrt() + 10.)).abs() < 0.001);
+        let stats = &m.delivery_stats[&player];
+        assert_eq!(1, stats.delivered);
+        assert_eq!(31., stats.average_journey_ticks);
+        assert_eq!(1., stats.average_transfers);
+
+        assert_eq!(1., DeliveryScoring::Speed { target_ticks: 100, max_bonus: 4 }.points(150));
+        assert_eq!(1., DeliveryScoring::Flat.points(0));
+    }
+
     #[test]
     pub fn passengers_for_change() {
         let player = PlayerId::new(0);
@@ -1501,14 +3094,14 @@ mod tests {
             StationType::Circle,
             (0., 0.),
         );
-        test_loc1.passengers.push(StationType::Triangle);
-        test_loc1.passengers.push(StationType::Square);
+        test_loc1.passengers.push(Passenger::new(StationType::Triangle, StationId(0), 0));
+        test_loc1.passengers.push(Passenger::new(StationType::Square, StationId(0), 0));
         let mut test_loc2 = Station::new (
             StationType::Circle,
             (0., 10.),
         );
-        test_loc2.passengers.push(StationType::Triangle);
-        test_loc2.passengers.push(StationType::Square);
+        test_loc2.passengers.push(Passenger::new(StationType::Triangle, StationId(1), 0));
+        test_loc2.passengers.push(Passenger::new(StationType::Square, StationId(1), 0));
         let test_loc3 = Station::new (
             StationType::Triangle,
             (10., 0.),
@@ -1531,12 +3124,12 @@ mod tests {
 
         let train = Train::new(LineId(0), (0., 10.), (0., 5.), true, StationId(0), StationId(1), 5.);
         m.trains.push(train);
-        assert_eq!(vec![StationType::Triangle, StationType::Square], m.get_station(&StationId(1)).unwrap().passengers);
+        assert_eq!(vec![StationType::Triangle, StationType::Square], destinations(&m.get_station(&StationId(1)).unwrap().passengers));
         assert_eq!(vec![StationType::Triangle], m.passengers_who_want_to_board(&m.trains[0], &StationId(1)));
 
         let train = Train::new(LineId(0), (0., 0.), (0., 5.), true, StationId(0), StationId(1), 5.);
         m.trains.push(train);
-        assert_eq!(vec![StationType::Triangle, StationType::Square], m.get_station(&StationId(0)).unwrap().passengers);
+        assert_eq!(vec![StationType::Triangle, StationType::Square], destinations(&m.get_station(&StationId(0)).unwrap().passengers));
         assert_eq!(Vec::<StationType>::new(), m.passengers_who_want_to_board(&m.trains[1], &StationId(0)));
     }
 
@@ -1571,7 +3164,7 @@ mod tests {
             StationType::Circle,
             (10., 20.),
         );
-        test_loc2.passengers.push(StationType::Triangle);
+        test_loc2.passengers.push(Passenger::new(StationType::Triangle, StationId(1), 0));
         let test_loc3 = Station::new (
             StationType::Square,
             (20., 0.),
@@ -1594,7 +3187,7 @@ mod tests {
         };
         let line2 = Line { edges: vec![ test_edge3 ], colour: (0., 0., 0.), owning_player: PlayerId::new(0) };
         let mut train = Train::new(LineId(1), (0., 10.), (0., 5.), false, StationId(1), StationId(2), 5.);
-        train.passengers.push(StationType::Triangle);
+        train.passengers.push(Passenger::new(StationType::Triangle, StationId(0), 0));
         let mut m = MetroModel::new();
         m.stations.push(test_loc1);
         m.stations.push(test_loc2);
@@ -1603,13 +3196,58 @@ mod tests {
         m.lines.push(line2);
         m.trains.push(train);
 
-        assert_eq!(vec![StationType::Triangle], m.trains[0].passengers);
+        assert_eq!(vec![StationType::Triangle], destinations(&m.trains[0].passengers));
         assert_eq!(vec![StationType::Triangle], m.passengers_who_want_to_alight(&m.trains[0], &StationId(1)));
 
-        assert_eq!(vec![StationType::Triangle], m.get_station(&StationId(1)).unwrap().passengers);
+        assert_eq!(vec![StationType::Triangle], destinations(&m.get_station(&StationId(1)).unwrap().passengers));
         assert_eq!(Vec::<StationType>::new(), m.passengers_who_want_to_board(&m.trains[0], &StationId(1)));
     }
 
+    #[test]
+    pub fn map_bounds_grow() {
+        let mut m = MetroModel::new();
+        m.stations.push(Station::new(StationType::Circle, (10., -30.)));
+        m.stations.push(Station::new(StationType::Square, (-45., 70.)));
+        m.stations.push(Station::new(StationType::Triangle, (300., 30.)));
+        m.fit_bounds_to_stations(100.);
+        assert_eq!((-145., -130., 400., 170.), (m.min_x, m.min_y, m.max_x, m.max_y));
+        assert!(!m.is_valid_station_pos(&(-400., 0.)));
+
+        m.expand_bounds(75., 500.);
+        assert_eq!((-220., -205., 475., 245.), (m.min_x, m.min_y, m.max_x, m.max_y));
+
+        m.expand_bounds(75., 500.);
+        assert_eq!((-295., -280., 500., 320.), (m.min_x, m.min_y, m.max_x, m.max_y));
+        assert!(m.is_valid_station_pos(&(-290., 0.)));
+    }
+
+    #[test]
+    pub fn placement_retries_away_from_terrain_and_edges() {
+        let mut m = MetroModel::new();
+        m.stations.push(Station::new(StationType::Circle, (0., 0.)));
+        m.stations.push(Station::new(StationType::Square, (0., 300.)));
+        m.terrain.push(Terrain::Lake { centre: (100., 0.), radius: 30. });
+        m.lines.push(Line {
+            edges: vec![ Edge { origin: StationId(0), destination: StationId(1), via_point: (0., 150.) } ],
+            colour: (0., 0., 0.),
+            owning_player: PlayerId::new(0),
+        });
+        let placement = StationPlacement::new();
+
+        let (rs, rr) = channel();
+        let random = TestRandom { r: rr };
+        // Near station 0, due east: in the lake
+        for r in [0., 0., 0., 0.25].iter() { rs.send(*r).unwrap(); }
+        // Near station 0, towards station 1: on top of the line
+        for r in [0., 0., 0.25, 0.25].iter() { rs.send(*r).unwrap(); }
+        // Near station 0, due west: clear, and the density roll passes
+        for r in [0., 0., 0.5, 0.25, 0.].iter() { rs.send(*r).unwrap(); }
+
+        let (x, y) = placement.find_position(&m, &random).expect("placement");
+        assert_eq!((-100, 0), (x.round() as i32, y.round() as i32));
+        assert!(random.r.try_recv().is_err());
+    }
+
     #[test]
     pub fn can_create_loop() {
         let player = PlayerId::new(0);
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::time::{ Duration, Instant };
+
+use protocol::Frame;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    // Most frames waiting at once, after snapshots have been coalesced
+    pub capacity: usize,
+    // Longest a frame may wait before the player is given up on
+    pub max_lag: Duration,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            capacity: 64,
+            max_lag: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LagStats {
+    pub queued: usize,
+    pub sent: u64,
+    // Snapshots replaced by a newer one before they were sent
+    pub coalesced: u64,
+    // How long the oldest waiting frame has been queued
+    pub lag: Duration,
+    pub max_lag: Duration,
+}
+
+#[derive(Debug)]
+struct Queued {
+    snapshot: Option<&'static str>,
+    frame: Frame,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+    frames: VecDeque<Queued>,
+    closed: Option<String>,
+    sent: u64,
+    coalesced: u64,
+    max_lag: Duration,
+}
+
+impl State {
+    fn lag(&self, now: Instant) -> Duration {
+        self.frames.iter().map(|q| now.duration_since(q.since)).max().unwrap_or_default()
+    }
+
+    // Gives up on the player, dropping whatever they haven't been sent
+    fn abandon(&mut self, reason: String) -> String {
+        self.frames.clear();
+        self.closed = Some(reason.clone());
+        reason
+    }
+}
+
+pub enum Popped {
+    Frame(Frame),
+    TimedOut,
+    Closed,
+}
+
+// Frames on their way from the game to one player's socket. Snapshots of the
+// same kind replace each other, so a slow player skips straight to the
+// latest state instead of working through a backlog. A player who still
+// can't keep up has their queue closed, which ends their connection.
+#[derive(Debug, Clone)]
+pub struct OutQueue {
+    limits: QueueLimits,
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl OutQueue {
+    pub fn new(limits: QueueLimits) -> Self {
+        let state = State {
+            frames: VecDeque::new(),
+            closed: None,
+            sent: 0,
+            coalesced: 0,
+            max_lag: Duration::default(),
+        };
+        OutQueue {
+            limits: limits,
+            shared: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    // Fails with the reason once the queue is closed
+    pub fn push(&self, snapshot: Option<&'static str>, frame: Frame) -> Result<(), String> {
+        self.push_at(snapshot, frame, Instant::now())
+    }
+
+    fn push_at(&self, snapshot: Option<&'static str>, frame: Frame, now: Instant) -> Result<(), String> {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().expect("out queue lock");
+        if let Some(ref reason) = state.closed {
+            return Err(reason.clone());
+        }
+        let lag = state.lag(now);
+        state.max_lag = state.max_lag.max(lag);
+        if lag > self.limits.max_lag {
+            ready.notify_all();
+            return Err(state.abandon(format!("{:?} behind", lag)));
+        }
+        let mut since = now;
+        if snapshot.is_some() {
+            if let Some(i) = state.frames.iter().position(|q| q.snapshot == snapshot) {
+                // The replacement has been waiting as long as what it replaces
+                since = state.frames.remove(i).map_or(now, |q| q.since);
+                state.coalesced += 1;
+            }
+        }
+        if state.frames.len() >= self.limits.capacity {
+            ready.notify_all();
+            let reason = format!("{} frames queued", state.frames.len());
+            return Err(state.abandon(reason));
+        }
+        state.frames.push_back(Queued { snapshot: snapshot, frame: frame, since: since });
+        ready.notify_one();
+        Ok(())
+    }
+
+    // Waits for the next frame, or None once the queue is closed and empty
+    pub fn pop(&self) -> Option<Frame> {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().expect("out queue lock");
+        loop {
+            if let Some(q) = state.frames.pop_front() {
+                state.sent += 1;
+                return Some(q.frame);
+            }
+            if state.closed.is_some() {
+                return None;
+            }
+            state = ready.wait(state).expect("out queue lock");
+        }
+    }
+
+    // Like `pop`, but stops waiting at `deadline`
+    pub fn pop_until(&self, deadline: Instant) -> Popped {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().expect("out queue lock");
+        loop {
+            if let Some(q) = state.frames.pop_front() {
+                state.sent += 1;
+                return Popped::Frame(q.frame);
+            }
+            if state.closed.is_some() {
+                return Popped::Closed;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Popped::TimedOut;
+            }
+            state = ready.wait_timeout(state, deadline - now).expect("out queue lock").0;
+        }
+    }
+
+    pub fn try_pop(&self) -> Option<Frame> {
+        let mut state = self.shared.0.lock().expect("out queue lock");
+        let q = state.frames.pop_front()?;
+        state.sent += 1;
+        Some(q.frame)
+    }
+
+    // Stops new frames being queued. Anything already queued still goes out.
+    pub fn close(&self, reason: &str) {
+        let (lock, ready) = &*self.shared;
+        let mut state = lock.lock().expect("out queue lock");
+        if state.closed.is_none() {
+            state.closed = Some(reason.to_string());
+        }
+        ready.notify_all();
+    }
+
+    pub fn stats(&self) -> LagStats {
+        let state = self.shared.0.lock().expect("out queue lock");
+        LagStats {
+            queued: state.frames.len(),
+            sent: state.sent,
+            coalesced: state.coalesced,
+            lag: state.lag(Instant::now()),
+            max_lag: state.max_lag,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn frame(s: &str) -> Frame {
+        Arc::new(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn snapshots_keep_only_the_latest() {
+        let queue = OutQueue::new(QueueLimits::default());
+        queue.push(Some("GameState"), frame("state 1")).unwrap();
+        queue.push(None, frame("you")).unwrap();
+        queue.push(Some("GameState"), frame("state 2")).unwrap();
+        queue.push(Some("LobbyCount"), frame("count")).unwrap();
+        queue.push(Some("GameState"), frame("state 3")).unwrap();
+
+        let stats = queue.stats();
+        assert_eq!(3, stats.queued);
+        assert_eq!(2, stats.coalesced);
+        assert_eq!(Some(frame("you")), queue.try_pop());
+        assert_eq!(Some(frame("count")), queue.try_pop());
+        assert_eq!(Some(frame("state 3")), queue.pop());
+        assert_eq!(None, queue.try_pop());
+        assert_eq!(3, queue.stats().sent);
+    }
+
+    #[test]
+    fn falling_behind_closes_the_queue() {
+        let limits = QueueLimits { capacity: 2, max_lag: Duration::from_secs(5) };
+        let full = OutQueue::new(limits);
+        full.push(None, frame("a")).unwrap();
+        full.push(None, frame("b")).unwrap();
+        assert_eq!(Err("2 frames queued".to_string()), full.push(None, frame("c")));
+        assert_eq!(None, full.pop());
+        assert!(full.push(Some("GameState"), frame("d")).is_err());
+
+        // Coalescing keeps the queue short but not the wait
+        let slow = OutQueue::new(limits);
+        let start = Instant::now();
+        slow.push_at(Some("GameState"), frame("1"), start).unwrap();
+        slow.push_at(Some("GameState"), frame("2"), start + Duration::from_secs(4)).unwrap();
+        assert_eq!(1, slow.stats().queued);
+        assert!(slow.push_at(Some("GameState"), frame("3"), start + Duration::from_secs(6)).is_err());
+        assert_eq!(Duration::from_secs(6), slow.stats().max_lag);
+    }
+
+    #[test]
+    fn closing_wakes_the_writer() {
+        let queue = OutQueue::new(QueueLimits::default());
+        let writer_queue = queue.clone();
+        let writer = thread::spawn(move || {
+            let mut written = Vec::new();
+            while let Some(f) = writer_queue.pop() {
+                written.push(f);
+            }
+            written
+        });
+        queue.push(None, frame("hello")).unwrap();
+        queue.close("player left");
+        assert_eq!(vec![frame("hello")], writer.join().unwrap());
+        assert_eq!(Err("player left".to_string()), queue.push(None, frame("again")));
+    }
+}
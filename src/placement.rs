@@ -0,0 +1,129 @@
+use std::f64::consts::PI;
+
+use metro_game::{ MetroModel, Point, StationId };
+use randoms::Random;
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct Hotspot {
+    pub centre: Point,
+    pub radius: f32,
+    pub weight: f32,
+}
+
+// Relative likelihood of a station appearing at a point. Hotspots add to the
+// base weight, fading out linearly to their radius.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct DensityMap {
+    pub base: f32,
+    pub hotspots: Vec<Hotspot>,
+}
+
+impl DensityMap {
+    pub fn uniform() -> Self {
+        DensityMap {
+            base: 1.,
+            hotspots: Vec::new(),
+        }
+    }
+
+    pub fn weight_at(&self, pos: &Point) -> f32 {
+        let mut weight = self.base;
+        for h in self.hotspots.iter() {
+            let distance = ((pos.0 - h.centre.0).powi(2) + (pos.1 - h.centre.1).powi(2)).sqrt();
+            if distance < h.radius {
+                weight += h.weight * (1. - distance / h.radius);
+            }
+        }
+        weight.max(0.)
+    }
+
+    pub fn max_weight(&self) -> f32 {
+        let boost: f32 = self.hotspots.iter().map(|h| h.weight.max(0.)).sum();
+        self.base.max(0.) + boost
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StationPlacement {
+    pub attempts: u32,
+    pub min_spacing: f32,
+    pub edge_clearance: f32,
+    pub cluster_chance: f64,
+    pub density: DensityMap,
+}
+
+impl StationPlacement {
+    pub fn new() -> Self {
+        StationPlacement {
+            attempts: 30,
+            min_spacing: 80.,
+            edge_clearance: 20.,
+            cluster_chance: 0.6,
+            density: DensityMap::uniform(),
+        }
+    }
+
+    // Poisson-disk style: candidates are mostly drawn from the ring between
+    // one and two spacings around an existing station, so new stations grow
+    // out of the current clusters without landing on top of them.
+    pub fn find_position<R: Random + ?Sized>(&self, model: &MetroModel, random: &R) -> Option<Point> {
+        let max_weight = self.density.max_weight();
+        if max_weight <= 0. {
+            return None;
+        }
+        for _ in 0..self.attempts {
+            let candidate = if model.station_count() > 0 && random.gen() < self.cluster_chance {
+                self.candidate_near_station(model, random)
+            } else {
+                self.candidate_anywhere(model, random)
+            };
+            if !self.is_acceptable(model, &candidate) {
+                continue;
+            }
+            let weight = self.density.weight_at(&candidate);
+            if random.gen() * (max_weight as f64) < weight as f64 {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn candidate_anywhere<R: Random + ?Sized>(&self, model: &MetroModel, random: &R) -> Point {
+        let ((min_x, min_y), (max_x, max_y)) = model.bounds();
+        let x = random.gen() as f32 * (max_x - min_x) + min_x;
+        let y = random.gen() as f32 * (max_y - min_y) + min_y;
+        (x, y)
+    }
+
+    fn candidate_near_station<R: Random + ?Sized>(&self, model: &MetroModel, random: &R) -> Point {
+        let count = model.station_count();
+        let index = ((random.gen() * count as f64) as usize).min(count - 1);
+        let (anchor_x, anchor_y) = model.get_station_pos(&StationId(index)).unwrap_or((0., 0.));
+        let angle = random.gen() * 2. * PI;
+        let distance = self.min_spacing * (1. + random.gen() as f32);
+        (anchor_x + distance * angle.cos() as f32, anchor_y + distance * angle.sin() as f32)
+    }
+
+    fn is_acceptable(&self, model: &MetroModel, pos: &Point) -> bool {
+        if !model.is_valid_station_pos(pos) {
+            return false;
+        }
+        if let Some(d) = model.distance_to_nearest_station(pos) {
+            if d < self.min_spacing {
+                return false;
+            }
+        }
+        if let Some(d) = model.distance_to_nearest_edge(pos) {
+            if d < self.edge_clearance {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Default for StationPlacement {
+    fn default() -> Self {
+        Self::new()
+    }
+}
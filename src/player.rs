@@ -1,7 +1,11 @@
-use std::net::{TcpListener, TcpStream, IpAddr, Ipv4Addr};
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream, IpAddr, Ipv4Addr, Shutdown, SocketAddr};
 use std::str::FromStr;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{ Duration, Instant };
 
 use serde_json;
 use tungstenite;
@@ -10,120 +14,403 @@ use tungstenite::error::Error;
 use tungstenite::protocol::Role;
 
 use events::{InputEvent};
+use heartbeat::Heartbeat;
+use metrics::METRICS;
 use metro_game::StateUpdate;
+use out_queue::{ LagStats, OutQueue, Popped, QueueLimits };
 use player_id::*;
+use protocol::{ self, Encoding, Frame, ServerHandshake };
+use proxy;
+use rate_limit::{ ConnectionLimiter, IpSlot, TokenBucket };
 use sexpect::*;
 
-fn handle_player_in(to_server: Sender<InputEvent>, in_stream: TcpStream, id: PlayerId) {
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    pub ping_interval: Duration,
+    // A player is dropped once this many pings in a row go unanswered
+    pub missed_pongs: u32,
+    // How long a player can stay connected without ever sending an action
+    pub idle_timeout: Duration,
+    pub queue: QueueLimits,
+    // Actions a player can send at once, and how many a second after that
+    pub action_burst: f64,
+    pub actions_per_second: f64,
+    // Actions over the limit are ignored with a warning. Each one uses up a
+    // strike, strikes come back at one a second, and a player with none
+    // left is dropped.
+    pub throttle_strikes: f64,
+    // Longer messages get the sender dropped. The check only happens once
+    // tungstenite has read the whole message, which it has no limit on, so
+    // this doesn't bound the memory one message can take.
+    pub max_message_size: usize,
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            ping_interval: Duration::from_secs(5),
+            missed_pongs: 3,
+            idle_timeout: Duration::from_secs(300),
+            queue: QueueLimits::default(),
+            action_burst: 10.,
+            actions_per_second: 5.,
+            throttle_strikes: 20.,
+            max_message_size: 4096,
+            max_connections_per_ip: 8,
+        }
+    }
+}
+
+// Warnings skip the game and go straight into the player's queue
+fn warn(to_player: &OutQueue, encoding: Encoding, text: String) {
+    let warning = StateUpdate::Warning(text);
+    let _ = to_player.push(warning.snapshot_kind(), Arc::new(protocol::encode(&warning, encoding)));
+}
+
+fn handle_player_in(
+    to_server: Sender<InputEvent>,
+    in_stream: TcpStream,
+    id: PlayerId,
+    to_player: OutQueue,
+    encoding: Encoding,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    settings: ConnectionSettings,
+) {
+    let connected = Instant::now();
+    let mut acted = false;
+    let mut actions = TokenBucket::new(settings.action_burst, settings.actions_per_second);
+    let mut strikes = TokenBucket::new(settings.throttle_strikes, 1.);
     let mut ws = WebSocket::from_raw_socket(in_stream, Role::Server);
     loop {
         match ws.read_message() {
+            Ok(Message::Pong(ref payload)) => {
+                let round_trip = heartbeat.lock().expect("heartbeat lock").pong(payload, Instant::now());
+                if let Some(round_trip) = round_trip {
+                    to_server.send(InputEvent::RoundTrip(id, round_trip))
+                      .sexpect("Failed to forward round trip to server");
+                }
+            }
             Ok(m) => {
-                let m_text = m.into_text().expect("message into text");
-                let json_m = serde_json::from_str(&m_text);
-                match json_m {
+                if !m.is_text() && !m.is_binary() {
+                    continue;
+                }
+                let abuse = if m.len() > settings.max_message_size {
+                    Some(format!("Message of {} bytes is over the {} byte limit", m.len(), settings.max_message_size))
+                } else if actions.take() {
+                    None
+                } else if strikes.take() {
+                    warn(&to_player, encoding, "Too many actions, some were ignored".to_string());
+                    continue;
+                } else {
+                    Some("Too many actions".to_string())
+                };
+                if let Some(reason) = abuse {
+                    warn!(player = id, reason = reason.as_str(); "Disconnecting abusive player");
+                    // The warning still goes out before the socket is shut
+                    warn(&to_player, encoding, format!("{}, disconnecting", reason));
+                    to_player.close(&reason);
+                    let _ = to_server.send(InputEvent::Disconnection(id));
+                    break;
+                }
+                // The frame type says how the message was encoded
+                let player_m = match m {
+                    Message::Text(ref text) => protocol::decode(text.as_bytes(), Encoding::Json),
+                    Message::Binary(ref bytes) => protocol::decode(bytes, Encoding::MessagePack),
+                    _ => continue,
+                };
+                match player_m {
                     Ok(player_m) => {
+                        acted = true;
+                        METRICS.message_in();
                         let translated_message = InputEvent::PlayerAction(id.clone(), player_m);
                         to_server.send(translated_message)
                           .sexpect("Failed to forward message to server");
                     }
                     Err(e) => {
-                        println!("Failed to deserialize message ({:?}): {:?}", e, m_text);
+                        warn!(player = id, error = e.as_str(), message:? = m; "Failed to deserialize message");
                     }
                 }
             }
             Err(Error::ConnectionClosed(_)) => {
                 let _ = to_server.send(InputEvent::Disconnection(id));
-                println!("{:?} disconnected", id);
+                info!(player = id; "Player disconnected");
                 break;
             }
+            // Reads time out every ping interval, which is when we check on
+            // players who connected but never did anything
+            Err(Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if !acted && connected.elapsed() > settings.idle_timeout {
+                    info!(player = id; "Idle since connecting, disconnecting");
+                    let _ = to_server.send(InputEvent::Disconnection(id));
+                    let _ = ws.get_ref().shutdown(Shutdown::Both);
+                    break;
+                }
+            }
             Err(e) => {
-                println!("{:?}: somoething else went wrong {:?}", id, e);
+                warn!(player = id, error:? = e; "Connection failed");
                 to_server.send(InputEvent::Disconnection(id))
                     .sexpect("letting the server know");
                 break;
             }
         }
     }
-    println!("Dropping {:?} in handler", id);
+    debug!(player = id; "Dropping in handler");
+}
+
+fn frame(bytes: Vec<u8>, encoding: Encoding) -> Message {
+    match encoding {
+        Encoding::Json => Message::text(String::from_utf8(bytes).expect("json is utf8")),
+        Encoding::MessagePack => Message::binary(bytes),
+    }
 }
 
-fn handle_player_out(from_server: Receiver<StateUpdate>, out_stream: TcpStream, id: PlayerId) {
+fn handle_player_out(from_server: OutQueue, out_stream: TcpStream, id: PlayerId, encoding: Encoding, heartbeat: Arc<Mutex<Heartbeat>>, ping_interval: Duration) {
     let mut ws = WebSocket::from_raw_socket(out_stream, Role::Server);
-    for m in from_server.iter() {
-        let serialized = serde_json::to_string(&m).expect("serualize");
-        if let Err(e) = ws.write_message(Message::text(serialized)) {
-            println!("Failed to forward message to {:?}: {:?}", id, e);
+    let mut next_ping = Instant::now() + ping_interval;
+    loop {
+        // Pings go out on time however busy the queue is
+        let (message, bytes) = if Instant::now() >= next_ping {
+            next_ping = Instant::now() + ping_interval;
+            match heartbeat.lock().expect("heartbeat lock").ping(Instant::now()) {
+                Ok(payload) => (Message::Ping(payload), None),
+                Err(reason) => {
+                    info!(player = id, reason = reason.as_str(); "Dropping unresponsive player");
+                    from_server.close(&reason);
+                    break;
+                }
+            }
+        } else {
+            match from_server.pop_until(next_ping) {
+                Popped::Frame(m) => (frame(m.to_vec(), encoding), Some(m.len())),
+                Popped::TimedOut => continue,
+                Popped::Closed => break,
+            }
+        };
+        if let Err(e) = ws.write_message(message) {
+            info!(player = id, error:? = e; "Failed to forward message");
+            from_server.close("socket closed");
             break;
         }
+        if let Some(bytes) = bytes {
+            METRICS.message_out(bytes);
+        }
+    }
+    // The game may have closed the queue on a player who fell behind, so
+    // make sure the reading side hears about it too
+    let _ = ws.get_ref().shutdown(Shutdown::Both);
+    debug!(player = id; "Dropping out handler");
+}
+
+// A websocket that's ready for its handshake, and its address's share of
+// the connection limit
+pub type NewConnection = (Box<WebSocket<TcpStream>>, IpSlot);
+
+// How long a new connection gets to send its request head
+const REQUEST_HEAD_WAIT: Duration = Duration::from_secs(5);
+// Connections being sniffed or passed on to the web frontend at once, each
+// with its own thread
+const MAX_PENDING_CONNECTIONS: usize = 256;
+
+// One of the pending connections, given back when dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn start(count: &Arc<AtomicUsize>) -> Option<Pending> {
+        if count.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_CONNECTIONS {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Pending(count.clone()))
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
-    println!("Dropping {:?} out handler", id);
 }
 
-pub fn connection_handler(new_player_sender: Sender<Box<WebSocket<TcpStream>>>, listener: TcpListener, murder_host: String) {
-    println!("listening");
+// Accepts players' websockets. With `web` set, anything that isn't a websocket
+// upgrade is passed on to the web frontend listening there, so both can share
+// one port.
+pub fn connection_handler(new_player_sender: Sender<NewConnection>, listener: TcpListener, murder_host: String, max_connections_per_ip: usize, web: Option<SocketAddr>) {
+    info!("Listening for players");
+    let limiter = ConnectionLimiter::new(max_connections_per_ip);
+    let pending = Arc::new(AtomicUsize::new(0));
     for incoming in listener.incoming() {
         let tcp_stream = incoming.expect("tcp stream");
+        let ip = tcp_stream.peer_addr().unwrap().ip();
+        // Web requests count against the address's limit as well as players
+        let slot = match limiter.acquire(ip) {
+            Some(slot) => slot,
+            None => {
+                warn!(ip:% = ip; "Too many connections, turning one away");
+                continue;
+            }
+        };
         let murderable = 
             Ipv4Addr::from_str(&murder_host).map(IpAddr::V4) == 
-            Ok(tcp_stream.peer_addr().unwrap().ip());
-        let accepted = tungstenite::accept(tcp_stream);
-        match accepted {
-            Ok(ws) => {
-                new_player_sender.send(Box::new(ws))
-                  .sexpect("Error sending websocket to player handler");
+            Ok(ip);
+        if murderable {
+            if let Err(e) = accept_websocket(tcp_stream, slot, &new_player_sender) {
+                info!(error:? = e; "Murdered, no longer listening");
+                return;
             }
-            Err(e) => {
-                if murderable {
-                    println!("quittin");
-                    return;
+            continue;
+        }
+        let pending = match Pending::start(&pending) {
+            Some(pending) => pending,
+            None => {
+                warn!(ip:% = ip; "Too many pending connections, turning one away");
+                continue;
+            }
+        };
+        let new_player_sender = new_player_sender.clone();
+        // Sniffed on its own thread so a quiet client can't hold up the rest
+        thread::spawn(move || {
+            let _pending = pending;
+            let head = match web {
+                Some(_) => proxy::peek_head(&tcp_stream, REQUEST_HEAD_WAIT).unwrap_or_default(),
+                None => Vec::new(),
+            };
+            match web {
+                Some(backend) if !proxy::is_websocket_upgrade(&head) => {
+                    if let Err(e) = proxy::forward(tcp_stream, backend) {
+                        warn!(error:% = e; "Failed to reach the web frontend");
+                    }
+                    drop(slot);
+                }
+                _ => {
+                    if let Err(e) = accept_websocket(tcp_stream, slot, &new_player_sender) {
+                        warn!(error:? = e; "Failed websocket connection");
+                    }
                 }
-                println!("Failed websocket connection {:?}", e);
             }
+        });
+    }
+}
+
+fn accept_websocket(tcp_stream: TcpStream, slot: IpSlot, new_player_sender: &Sender<NewConnection>) -> Result<(), String> {
+    let ws = tungstenite::accept(tcp_stream).map_err(|e| format!("{:?}", e))?;
+    new_player_sender.send((Box::new(ws), slot))
+      .sexpect("Error sending websocket to player handler");
+    Ok(())
+}
+
+// Waits for the client's Hello, then joins it to the game with a Welcome. A
+// client that gets the handshake wrong is sent a Reject and dropped.
+// The slot is held for as long as the player is connected.
+fn handle_handshake(mut ws: WebSocket<TcpStream>, _slot: IpSlot, to_server: Sender<InputEvent>, id: PlayerId, settings: ConnectionSettings) {
+    // Clients that never say hello are dropped along with idle ones
+    let _ = ws.get_ref().set_read_timeout(Some(settings.idle_timeout));
+    let hello = match ws.read_message() {
+        Ok(ref m) if m.len() > settings.max_message_size => Err("Hello is too long".to_string()),
+        Ok(m) => m.into_text().map_err(|e| format!("{:?}", e)).and_then(|text| protocol::check_hello(&text)),
+        Err(e) => {
+            info!(player = id, error:? = e; "Left before saying hello");
+            return;
+        }
+    };
+    let (client_name, encoding) = match hello {
+        Ok(hello) => hello,
+        Err(reason) => {
+            info!(player = id, reason = reason.as_str(); "Rejecting client");
+            let reject = serde_json::to_string(&ServerHandshake::Reject { reason: reason }).expect("serialize reject");
+            let _ = ws.write_message(Message::text(reject));
+            let _ = ws.close(None);
+            let _ = ws.write_pending();
+            return;
+        }
+    };
+    info!(player = id, client = client_name.as_str(), encoding:? = encoding; "Client said hello");
+    let to_player = OutQueue::new(settings.queue);
+    let heartbeat = Arc::new(Mutex::new(Heartbeat::new(settings.missed_pongs)));
+    let welcome = ServerHandshake::Welcome { protocol_version: protocol::PROTOCOL_VERSION, player: id, encoding: encoding };
+    let welcome = serde_json::to_string(&welcome).expect("serialize welcome");
+    if let Err(e) = ws.write_message(Message::text(welcome)) {
+        warn!(player = id, error:? = e; "Failed to welcome player");
+        return;
+    }
+    match to_server.send(InputEvent::Connection(id, Player::new(to_player.clone(), encoding))) {
+        Ok(_) => {
+            let s = ws.get_ref().try_clone().expect("stream cloning");
+            let s2 = ws.get_ref().try_clone().expect("stream cloning");
+            let _ = s.set_read_timeout(Some(settings.ping_interval));
+            let out_heartbeat = heartbeat.clone();
+            let out_queue = to_player.clone();
+            thread::spawn(move || handle_player_out(out_queue, s2, id, encoding, out_heartbeat, settings.ping_interval));
+            handle_player_in(to_server, s, id, to_player, encoding, heartbeat, settings);
+        }
+        Err(e) => {
+            error!(player = id, error:? = e; "Failed sending player to game");
+            let _ = ws.write_message(Message::text("Connection failed"));
         }
     }
 }
 
 pub fn player_handler(
-    player_receiver: Receiver<Box<WebSocket<TcpStream>>>,
+    player_receiver: Receiver<NewConnection>,
     to_server: Sender<InputEvent>,
+    settings: ConnectionSettings,
 ) {
     let mut next_id = 0u16;
-    for mut player in player_receiver.iter() {
-        println!("New Player {:?}!", next_id);
-        let (to_player_s, to_player_r) = channel();
+    for (player, slot) in player_receiver.iter() {
+        debug!(player = next_id; "New player");
         let id = PlayerId::new(next_id);
-        match to_server.send(InputEvent::Connection(id, Player::new(to_player_s))) {
-            Ok(_) => {
-                let s = player.get_ref().try_clone().expect("stream cloning");
-                let s2 = player.get_ref().try_clone().expect("stream cloning");
-                let to_s = to_server.clone();
-                let p_id = id.clone();
-                let p_id2 = id.clone();
-                thread::spawn(move || handle_player_in(to_s, s, p_id));
-                thread::spawn(move || handle_player_out(to_player_r, s2, p_id2));
-                next_id = next_id.wrapping_add(1);
-            }
-            Err(e) => {
-                println!("Failed sending player to game: {:?}", e);
-                let _ = player.write_message(Message::text("Connection failed"));
-            }
-        }
+        let to_s = to_server.clone();
+        thread::spawn(move || handle_handshake(*player, slot, to_s, id, settings));
+        next_id = if next_id >= MAX_CONNECTION_ID { 0 } else { next_id + 1 };
     }
 }
 
 #[derive(Debug)]
 pub struct Player {
-    sender: Sender<StateUpdate>,
+    queue: Option<OutQueue>,
+    encoding: Encoding,
 }
 
 impl Player {
-    pub fn new(s: Sender<StateUpdate>) -> Self {
-        Player { sender: s }
+    pub fn new(queue: OutQueue, encoding: Encoding) -> Self {
+        Player { queue: Some(queue), encoding: encoding }
+    }
+
+    // For games driven with `SteppedGame::step`, where updates are returned
+    // to the caller rather than sent anywhere.
+    pub fn detached() -> Self {
+        Player { queue: None, encoding: Encoding::Json }
     }
 
-    pub fn send_message(&self, message: StateUpdate) {
-        self.sender.send(message)
-          .sexpect("Failed to send message to player handler");
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // An error means the player can't keep up and should be dropped
+    pub fn send_message(&self, message: StateUpdate) -> Result<(), String> {
+        if self.queue.is_none() {
+            return Ok(());
+        }
+        self.send_frame(message.snapshot_kind(), Arc::new(protocol::encode(&message, self.encoding)))
+    }
+
+    // For updates already encoded with this player's encoding
+    pub fn send_frame(&self, snapshot: Option<&'static str>, frame: Frame) -> Result<(), String> {
+        match self.queue {
+            Some(ref queue) => queue.push(snapshot, frame),
+            None => Ok(()),
+        }
+    }
+
+    pub fn lag_stats(&self) -> Option<LagStats> {
+        self.queue.as_ref().map(|q| q.stats())
+    }
+}
+
+// Lets the output thread finish once the game is done with the player
+impl Drop for Player {
+    fn drop(&mut self) {
+        if let Some(ref queue) = self.queue {
+            queue.close("player left");
+        }
     }
 }
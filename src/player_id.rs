@@ -1,5 +1,13 @@
+use std::fmt;
 
-#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+use log::kv::{ ToValue, Value };
+
+// Bots take ids down from the top of the range and connections count up
+// from 0, wrapping before they reach the bots'
+pub const BOT_IDS: u16 = 16;
+pub const MAX_CONNECTION_ID: u16 = u16::MAX - BOT_IDS;
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 pub struct PlayerId(u16);
 
 impl PlayerId {
@@ -8,3 +16,15 @@ impl PlayerId {
     }
 }
 
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// So log lines can carry the player as a field
+impl ToValue for PlayerId {
+    fn to_value(&self) -> Value<'_> {
+        Value::from(self.0)
+    }
+}
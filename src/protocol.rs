@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use rmp_serde;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{ self, Value };
+
+use metro_game::{ PlayerAction, StateUpdate };
+use player_id::PlayerId;
+
+// Bump this whenever a change to any message below would break an existing
+// client, and keep www/static/metro.js in step with it.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// How messages after the handshake are encoded. JSON goes in text frames
+// and stays the default since it's easy to read while debugging;
+// MessagePack goes in binary frames and is smaller and quicker to build.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+// The first message a client sends, always as JSON. Nothing else is accepted
+// until the server has answered it.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum ClientHandshake {
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum ServerHandshake {
+    Welcome { protocol_version: u32, player: PlayerId, encoding: Encoding },
+    Reject { reason: String },
+}
+
+// Returns the client's name and the encoding it asked for if it speaks our
+// protocol, or why it doesn't.
+pub fn check_hello(message: &str) -> Result<(String, Encoding), String> {
+    match serde_json::from_str(message) {
+        Ok(ClientHandshake::Hello { protocol_version, client_name, encoding }) => {
+            if protocol_version == PROTOCOL_VERSION {
+                Ok((client_name, encoding))
+            } else {
+                Err(format!(
+                    "{} speaks protocol version {} but this server speaks version {}",
+                    client_name, protocol_version, PROTOCOL_VERSION))
+            }
+        }
+        Err(e) => Err(format!("Expected a Hello message first ({})", e)),
+    }
+}
+
+// An update encoded for the wire. Shared so a broadcast is only encoded once
+// however many players it goes to.
+pub type Frame = Arc<Vec<u8>>;
+
+pub fn encode<T: Serialize>(message: &T, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => serde_json::to_vec(message).expect("json encode"),
+        // Named so structs become maps, which clients can read without
+        // knowing the field order
+        Encoding::MessagePack => rmp_serde::to_vec_named(message).expect("msgpack encode"),
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], encoding: Encoding) -> Result<T, String> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+        Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+    }
+}
+
+fn schema_value(schema: RootSchema) -> Value {
+    serde_json::to_value(schema).expect("schema to json")
+}
+
+// A schema for every message either side can send, keyed by message type.
+pub fn schema() -> Value {
+    json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "client": {
+            "ClientHandshake": schema_value(schema_for!(ClientHandshake)),
+            "PlayerAction": schema_value(schema_for!(PlayerAction)),
+        },
+        "server": {
+            "ServerHandshake": schema_value(schema_for!(ServerHandshake)),
+            "StateUpdate": schema_value(schema_for!(StateUpdate)),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+    use maps;
+    use metro_game::{ LineId, StationId };
+
+    #[test]
+    fn hello_handshake() {
+        assert_eq!(
+            Ok(("metro.js".to_string(), Encoding::Json)),
+            check_hello(r#"{"Hello":{"protocol_version":2,"client_name":"metro.js"}}"#));
+        assert_eq!(
+            Ok(("bot".to_string(), Encoding::MessagePack)),
+            check_hello(r#"{"Hello":{"protocol_version":2,"client_name":"bot","encoding":"MessagePack"}}"#));
+        assert_eq!(
+            Err("old.js speaks protocol version 1 but this server speaks version 2".to_string()),
+            check_hello(r#"{"Hello":{"protocol_version":1,"client_name":"old.js"}}"#));
+        assert!(check_hello(r#"{"StartGame":null}"#).unwrap_err().starts_with("Expected a Hello message first"));
+
+        let welcome = ServerHandshake::Welcome { protocol_version: PROTOCOL_VERSION, player: PlayerId::new(3), encoding: Encoding::Json };
+        assert_eq!(r#"{"Welcome":{"protocol_version":2,"player":3,"encoding":"Json"}}"#, serde_json::to_string(&welcome).unwrap());
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let action = PlayerAction::InsertAtLineEnd(LineId(2), StationId(5));
+        for encoding in [Encoding::Json, Encoding::MessagePack].iter() {
+            match decode(&encode(&action, *encoding), *encoding) {
+                Ok(PlayerAction::InsertAtLineEnd(LineId(2), StationId(5))) => {}
+                other => panic!("{:?} came back as {:?}", encoding, other),
+            }
+        }
+        assert_eq!(r#"{"InsertAtLineEnd":[2,5]}"#.as_bytes(), &encode(&action, Encoding::Json)[..]);
+        assert!(decode::<PlayerAction>(b"\xc1", Encoding::MessagePack).is_err());
+
+        let model = maps::builtin_maps()[0].build_model();
+        let update = StateUpdate::GameState(model);
+        assert!(encode(&update, Encoding::MessagePack).len() < encode(&update, Encoding::Json).len());
+    }
+
+    // Any change to a message type shows up here. Regenerate the file with
+    // UPDATE_SCHEMA=1 cargo test, and bump PROTOCOL_VERSION if old clients
+    // can no longer talk to the server.
+    #[test]
+    fn schema_matches_checked_in_copy() {
+        let path = "protocol/schema.json";
+        let generated = serde_json::to_string_pretty(&schema()).unwrap() + "\n";
+        if env::var("UPDATE_SCHEMA").is_ok() {
+            fs::write(path, &generated).unwrap();
+        }
+        let checked_in = fs::read_to_string(path).unwrap();
+        // Plain assert: a line diff of the whole schema takes far too long
+        assert!(checked_in == generated, "{} is out of date, rerun the tests with UPDATE_SCHEMA=1", path);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io;
+use std::net::{ Shutdown, SocketAddr, TcpStream };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// Longest request head we'll look through for an Upgrade header
+const MAX_HEAD: usize = 8192;
+
+// Looks at the start of a request without consuming it, so whoever handles
+// the connection still sees all of it. Stops at the end of the headers, when
+// the client goes quiet for `wait` or at `MAX_HEAD` bytes.
+pub fn peek_head(stream: &TcpStream, wait: Duration) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_HEAD];
+    let deadline = Instant::now() + wait;
+    stream.set_read_timeout(Some(wait))?;
+    let result = loop {
+        let n = match stream.peek(&mut buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => 0,
+            Err(e) => break Err(e),
+        };
+        let head = &buf[..n];
+        if n == 0 || n == buf.len() || contains(head, b"\r\n\r\n") || Instant::now() >= deadline {
+            break Ok(head.to_vec());
+        }
+        // Peeking again straight away would just see the same bytes
+        thread::sleep(Duration::from_millis(5));
+    };
+    stream.set_read_timeout(None)?;
+    result
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// The game's websocket lives at /ws. Upgrades anywhere else are left to the
+// web frontend.
+pub fn is_websocket_upgrade(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let path = head.lines().next().and_then(|line| line.split(' ').nth(1)).unwrap_or("");
+    if path.split('?').next() != Some("/ws") {
+        return false;
+    }
+    head.lines().skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut halves = line.splitn(2, ':');
+            Some((halves.next()?.trim().to_lowercase(), halves.next()?.trim().to_lowercase()))
+        })
+        .any(|(name, value)| name == "upgrade" && value.split(',').any(|v| v.trim() == "websocket"))
+}
+
+// Passes the connection through to `backend` byte for byte until either side
+// hangs up
+pub fn forward(client: TcpStream, backend: SocketAddr) -> io::Result<()> {
+    let server = TcpStream::connect(backend)?;
+    let (mut client_in, mut server_out) = (client.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_in, &mut server_out);
+        let _ = server_out.shutdown(Shutdown::Write);
+    });
+    let (mut server_in, mut client_out) = (server, client);
+    let _ = io::copy(&mut server_in, &mut client_out);
+    let _ = client_out.shutdown(Shutdown::Both);
+    let _ = upstream.join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spots_upgrades() {
+        let upgrade = b"GET /ws HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: WebSocket\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert!(is_websocket_upgrade(upgrade));
+        assert!(is_websocket_upgrade(b"GET /ws?v=1 HTTP/1.1\r\nupgrade: h2c, websocket\r\n\r\n"));
+        assert!(!is_websocket_upgrade(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"));
+        assert!(!is_websocket_upgrade(b"GET /metrics HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        // Only headers count, not the body or the request line
+        assert!(!is_websocket_upgrade(b"GET /Upgrade:websocket HTTP/1.1\r\n\r\nUpgrade: websocket\r\n"));
+        assert!(!is_websocket_upgrade(b""));
+    }
+}
@@ -1,8 +1,18 @@
+use std::cell::RefCell;
 use std::sync::mpsc::Receiver;
-use rand::{Rng, thread_rng};
+use rand::{Rng, SeedableRng, XorShiftRng, thread_rng};
 
 pub trait Random {
     fn gen(&self) -> f64;
+
+    // What the numbers come from, if they can be had again
+    fn seed(&self) -> Option<u64> {
+        None
+    }
+
+    // Called as each game starts, so that its seed, if any, gives back that
+    // game alone
+    fn reseed(&mut self) {}
 }
 
 pub struct Always1Random;
@@ -31,3 +41,48 @@ impl Random for RealRandom {
     }
 }
 
+
+pub struct SeededRandom {
+    seed: u64,
+    rng: RefCell<XorShiftRng>,
+    // Whether a game has started from the current seed
+    used: bool,
+}
+
+fn xorshift(seed: u64) -> XorShiftRng {
+    let low = seed as u32;
+    let high = (seed >> 32) as u32;
+    // XorShift can't be seeded with all zeroes
+    XorShiftRng::from_seed([low, high, low ^ 0x9E37_79B9, high | 1])
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom { seed: seed, rng: RefCell::new(xorshift(seed)), used: false }
+    }
+
+    // A seed nobody picked, still written down so the run can be had again
+    pub fn fresh() -> Self {
+        SeededRandom::new(thread_rng().gen())
+    }
+}
+
+impl Random for SeededRandom {
+    fn gen(&self) -> f64 {
+        self.rng.borrow_mut().gen()
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(self.seed)
+    }
+
+    // The first game gets the seed this was made with, and every game after
+    // it a fresh one
+    fn reseed(&mut self) {
+        if self.used {
+            self.seed = thread_rng().gen();
+        }
+        self.rng = RefCell::new(xorshift(self.seed));
+        self.used = true;
+    }
+}
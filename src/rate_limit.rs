@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::Instant;
+
+// Allows bursts of up to `capacity` and `per_second` on average after that.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, per_second: f64) -> Self {
+        TokenBucket {
+            capacity: capacity,
+            per_second: per_second,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000_f64;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// The same, but counted in game ticks, so that stepped games behave the
+// same however fast they're run.
+#[derive(Debug, Clone)]
+pub struct TickBucket {
+    capacity: f64,
+    per_tick: f64,
+    tokens: f64,
+    last: u64,
+}
+
+impl TickBucket {
+    pub fn new(capacity: f64, per_tick: f64, now: u64) -> Self {
+        TickBucket {
+            capacity: capacity,
+            per_tick: per_tick,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    pub fn take(&mut self, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.last);
+        self.tokens = (self.tokens + elapsed as f64 * self.per_tick).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Counts open connections from each address.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Holds one of an address's connections open until dropped
+#[derive(Debug)]
+pub struct IpSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        ConnectionLimiter {
+            max_per_ip: max_per_ip,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn acquire(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().expect("connection limiter lock");
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot { ip: ip, open: self.open.clone() })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().expect("connection limiter lock");
+        let empty = match open.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if empty {
+            open.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut bucket = TokenBucket::new(2., 4.);
+        let start = bucket.last;
+        assert!(bucket.take_at(start));
+        assert!(bucket.take_at(start));
+        assert!(!bucket.take_at(start));
+        assert!(!bucket.take_at(start + Duration::from_millis(200)));
+        assert!(bucket.take_at(start + Duration::from_millis(250)));
+        // Never more than the burst, however long it's been
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take_at(later));
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn connections_per_ip() {
+        let limiter = ConnectionLimiter::new(2);
+        let home = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let away = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let first = limiter.acquire(home).unwrap();
+        let _second = limiter.acquire(home).unwrap();
+        assert!(limiter.acquire(home).is_none());
+        assert!(limiter.acquire(away).is_some());
+        drop(first);
+        assert!(limiter.acquire(home).is_some());
+    }
+}
@@ -0,0 +1,331 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{ self, OpenOptions };
+use std::io::{ ErrorKind, Write };
+use std::path::PathBuf;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use serde_json;
+
+use metro_game::{ StationId, TeamId };
+use player_id::PlayerId;
+use ruleset;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum GameEnd {
+    // The station stayed overcrowded for too long
+    Overcrowded(StationId),
+    // Every player left, leaving only bots
+    Abandoned,
+}
+
+impl fmt::Display for GameEnd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GameEnd::Overcrowded(ref station) => write!(f, "Station {} overcrowded", station.0),
+            GameEnd::Abandoned => write!(f, "Everyone left"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PlayerResult {
+    pub player: PlayerId,
+    pub name: String,
+    pub bot: bool,
+    // Results from before teams have none
+    #[serde(default)]
+    pub team: Option<TeamId>,
+    pub score: f32,
+    pub lines: usize,
+    pub trains: usize,
+    pub delivered: u32,
+}
+
+impl PlayerResult {
+    // Players who never set a name go by their connection id, which the
+    // next player to connect after a restart could have too
+    pub fn is_named(&self) -> bool {
+        self.bot || self.name != unnamed(self.player)
+    }
+}
+
+pub fn unnamed(player: PlayerId) -> String {
+    format!("Player {}", player)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GameResult {
+    pub map: String,
+    pub scoring: String,
+    pub visibility: String,
+    // Results from before rulesets called it the mode, and those from
+    // before co-op were all competitive
+    #[serde(default = "competitive", alias = "mode")]
+    pub ruleset: String,
+    // Each game's own, where it was run from one
+    pub seed: Option<u64>,
+    pub weeks: u32,
+    pub ticks: u64,
+    pub ended: GameEnd,
+    pub players: Vec<PlayerResult>,
+    // Empty unless it was a team game
+    #[serde(default)]
+    pub teams: Vec<TeamResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TeamResult {
+    pub team: TeamId,
+    pub score: f32,
+}
+
+fn competitive() -> String {
+    "Competitive".to_string()
+}
+
+impl GameResult {
+    // In a team game the whole of the best team wins
+    pub fn winners(&self) -> Vec<&PlayerResult> {
+        let teams = team_totals(&self.players);
+        let team_score = |team: TeamId| teams.iter().find(|t| t.team == team).map(|t| t.score);
+        let score = |p: &PlayerResult| p.team.and_then(team_score).unwrap_or(p.score);
+        let best = self.players.iter().map(score).fold(None, |best: Option<f32>, s| Some(best.map_or(s, |b| b.max(s))));
+        self.players.iter().filter(|p| Some(score(p)) == best).collect()
+    }
+}
+
+pub fn team_totals(players: &[PlayerResult]) -> Vec<TeamResult> {
+    let mut totals = BTreeMap::new();
+    for p in players.iter() {
+        if let Some(team) = p.team {
+            *totals.entry(team).or_insert(0.) += p.score;
+        }
+    }
+    totals.into_iter().map(|(team, score)| TeamResult { team: team, score: score }).collect()
+}
+
+// A result as kept on disk, stamped with when it was recorded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResult {
+    // Seconds since the Unix epoch
+    pub finished_at: u64,
+    #[serde(flatten)]
+    pub result: GameResult,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Standing {
+    pub name: String,
+    pub bot: bool,
+    pub games: u32,
+    pub wins: u32,
+    pub best_score: f32,
+    pub total_score: f32,
+    pub delivered: u32,
+}
+
+// Finished games, one JSON object per line of a file. Lines are only ever
+// appended, so a crash can cost at most the game being written.
+#[derive(Debug, Clone)]
+pub struct ResultsStore {
+    path: PathBuf,
+}
+
+impl ResultsStore {
+    pub fn new(path: PathBuf) -> Self {
+        ResultsStore { path: path }
+    }
+
+    // METROLTI_RESULTS names the file, `results.jsonl` if it's unset
+    pub fn from_env() -> Self {
+        ResultsStore::new(env::var("METROLTI_RESULTS").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("results.jsonl")))
+    }
+
+    pub fn record(&self, result: &GameResult) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let recorded = RecordedResult { finished_at: now.as_secs(), result: result.clone() };
+        let line = serde_json::to_string(&recorded).map_err(|e| e.to_string())?;
+        OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut f| writeln!(f, "{}", line))
+            .map_err(|e| format!("Could not record result in {}: {}", self.path.display(), e))
+    }
+
+    // Oldest first. Lines that don't parse are skipped.
+    pub fn load(&self) -> Result<Vec<RecordedResult>, String> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Could not read {}: {}", self.path.display(), e)),
+        };
+        let mut results = Vec::new();
+        for (i, line) in text.lines().enumerate().filter(|&(_, l)| !l.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(result) => results.push(result),
+                Err(e) => warn!(file:% = self.path.display(), line = i + 1, error:% = e; "Skipping unreadable result"),
+            }
+        }
+        Ok(results)
+    }
+}
+
+// Everyone who has played under a name, with the most wins first and best
+// score breaking ties. Names are whatever players call themselves, so
+// everyone playing as "ada" adds to the same standing. Games where
+// everyone builds together, like co-op, have nobody to beat and are left
+// out.
+pub fn leaderboard(results: &[RecordedResult]) -> Vec<Standing> {
+    let mut standings: BTreeMap<(String, bool), Standing> = BTreeMap::new();
+    let together = |r: &GameResult| match ruleset::ruleset_by_name(&r.ruleset) {
+        Some(r) => r.shared_network(),
+        None => false,
+    };
+    for recorded in results.iter().filter(|r| !together(&r.result)) {
+        let winners: Vec<PlayerId> = recorded.result.winners().iter().map(|p| p.player).collect();
+        for p in recorded.result.players.iter().filter(|p| p.is_named()) {
+            let standing = standings.entry((p.name.clone(), p.bot)).or_insert_with(|| Standing {
+                name: p.name.clone(),
+                bot: p.bot,
+                games: 0,
+                wins: 0,
+                best_score: p.score,
+                total_score: 0.,
+                delivered: 0,
+            });
+            standing.games += 1;
+            if winners.contains(&p.player) {
+                standing.wins += 1;
+            }
+            standing.best_score = standing.best_score.max(p.score);
+            standing.total_score += p.score;
+            standing.delivered += p.delivered;
+        }
+    }
+    let mut standings: Vec<Standing> = standings.into_values().collect();
+    standings.sort_by(|a, b| b.wins.cmp(&a.wins)
+        .then(b.best_score.partial_cmp(&a.best_score).unwrap_or(Ordering::Equal)));
+    standings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn player(id: u16, name: &str, score: f32) -> PlayerResult {
+        PlayerResult {
+            player: PlayerId::new(id),
+            name: name.to_string(),
+            bot: false,
+            team: None,
+            score: score,
+            lines: 1,
+            trains: 1,
+            delivered: score as u32,
+        }
+    }
+
+    fn result(players: Vec<PlayerResult>) -> GameResult {
+        let teams = team_totals(&players);
+        GameResult {
+            map: "Classic".to_string(),
+            scoring: "LastMile".to_string(),
+            visibility: "Full".to_string(),
+            ruleset: "Competitive".to_string(),
+            seed: Some(7),
+            weeks: 3,
+            ticks: 12600,
+            ended: GameEnd::Overcrowded(StationId(2)),
+            players: players,
+            teams: teams,
+        }
+    }
+
+    #[test]
+    fn results_are_kept_between_loads() {
+        let path = env::temp_dir().join(format!("metrolti-results-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let store = ResultsStore::new(path.clone());
+        assert_eq!(Ok(Vec::new()), store.load());
+
+        let first = result(vec![player(0, "ada", 12.), player(1, "bo", 4.)]);
+        let mut teammate = player(0, "bo", 9.);
+        teammate.team = Some(TeamId(1));
+        let second = result(vec![teammate]);
+        assert_eq!(vec![TeamResult { team: TeamId(1), score: 9. }], second.teams);
+        store.record(&first).unwrap();
+        store.record(&second).unwrap();
+        let loaded = store.load().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(vec![first, second], loaded.into_iter().map(|r| r.result).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn older_results_still_load() {
+        let mut json = serde_json::to_value(result(vec![player(0, "ada", 12.)])).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("ruleset");
+        fields.remove("teams");
+        let oldest: GameResult = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!("Competitive", oldest.ruleset);
+        json.as_object_mut().unwrap().insert("mode".to_string(), serde_json::Value::from("Coop"));
+        let coop: GameResult = serde_json::from_value(json).unwrap();
+        assert_eq!("Coop", coop.ruleset);
+    }
+
+    #[test]
+    fn leaderboard_counts_wins() {
+        let recorded = |players| RecordedResult { finished_at: 0, result: result(players) };
+        let results = vec![
+            recorded(vec![player(0, "ada", 12.), player(1, "bo", 4.)]),
+            recorded(vec![player(0, "bo", 9.), player(1, "cy", 9.)]),
+            recorded(vec![player(0, "bo", 20.), player(1, "ada", 2.)]),
+        ];
+        let standings = leaderboard(&results);
+        let summary: Vec<(&str, u32, u32, f32)> = standings.iter()
+            .map(|s| (s.name.as_str(), s.games, s.wins, s.best_score))
+            .collect();
+        // Ties are wins for everyone tied
+        assert_eq!(vec![("bo", 3, 2, 20.), ("ada", 2, 1, 12.), ("cy", 1, 1, 9.)], summary);
+        assert_eq!(33., standings[0].total_score);
+    }
+
+    #[test]
+    fn coop_games_add_no_wins() {
+        let mut coop = result(vec![player(0, "ada", 7.), player(1, "bo", 7.)]);
+        coop.ruleset = "Coop".to_string();
+        let results = vec![
+            RecordedResult { finished_at: 0, result: coop },
+            RecordedResult { finished_at: 1, result: result(vec![player(0, "ada", 2.), player(1, "bo", 5.)]) },
+        ];
+        let standings = leaderboard(&results);
+        let wins: Vec<(&str, u32, u32)> = standings.iter().map(|s| (s.name.as_str(), s.games, s.wins)).collect();
+        assert_eq!(vec![("bo", 1, 1), ("ada", 1, 0)], wins);
+    }
+
+    #[test]
+    fn unnamed_players_are_left_off() {
+        let mut bot = player(1, "Player 1", 3.);
+        bot.bot = true;
+        let results = vec![RecordedResult {
+            finished_at: 0,
+            result: result(vec![player(0, "Player 0", 12.), bot, player(2, "Player 5", 1.)]),
+        }];
+        let names: Vec<String> = leaderboard(&results).into_iter().map(|s| s.name).collect();
+        assert_eq!(vec!["Player 1".to_string(), "Player 5".to_string()], names);
+    }
+
+    #[test]
+    fn teams_win_together() {
+        let mut players = vec![player(0, "ada", 12.), player(1, "bo", 1.), player(2, "cy", 8.), player(3, "di", 8.)];
+        for (p, team) in players.iter_mut().zip(vec![0, 0, 1, 1]) {
+            p.team = Some(TeamId(team));
+        }
+        let game = result(players);
+        let winners: Vec<&str> = game.winners().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(vec!["cy", "di"], winners);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicU32, Ordering };
+
+use metro_game::{ MetroModel, TeamId };
+use player_id::PlayerId;
+
+pub type RoomId = u32;
+
+// What the web API says about a room
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomSummary {
+    pub id: RoomId,
+    pub phase: String,
+    pub players: usize,
+    pub bots: usize,
+    pub map: String,
+    pub scoring: String,
+    pub visibility: String,
+    pub ruleset: String,
+    pub week: u32,
+    pub tick: u64,
+    // None while the room's visibility keeps scores from players
+    pub scores: Option<HashMap<PlayerId, f32>>,
+    // Empty in games without teams
+    pub team_scores: Option<HashMap<TeamId, f32>>,
+}
+
+#[derive(Debug)]
+struct Room {
+    summary: RoomSummary,
+    state: Option<Arc<MetroModel>>,
+}
+
+// The rooms running in this process, as their games last published them.
+// Games write, the web frontend reads.
+#[derive(Debug)]
+pub struct Rooms {
+    next_id: AtomicU32,
+    rooms: RwLock<BTreeMap<RoomId, Room>>,
+}
+
+pub static ROOMS: Rooms = Rooms::new();
+
+// A game's entry in the registry, removed again when dropped
+#[derive(Debug)]
+pub struct RoomHandle<'a> {
+    rooms: &'a Rooms,
+    id: RoomId,
+}
+
+impl Rooms {
+    pub const fn new() -> Self {
+        Rooms {
+            next_id: AtomicU32::new(0),
+            rooms: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn open(&self) -> RoomHandle<'_> {
+        RoomHandle {
+            rooms: self,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.rooms.read().expect("rooms lock").len()
+    }
+
+    pub fn list(&self) -> Vec<RoomSummary> {
+        self.rooms.read().expect("rooms lock").values().map(|r| r.summary.clone()).collect()
+    }
+
+    pub fn summary(&self, id: RoomId) -> Option<RoomSummary> {
+        self.rooms.read().expect("rooms lock").get(&id).map(|r| r.summary.clone())
+    }
+
+    // The room's latest state, if it has one that anyone may see
+    pub fn state(&self, id: RoomId) -> Option<Option<Arc<MetroModel>>> {
+        self.rooms.read().expect("rooms lock").get(&id).map(|r| r.state.clone())
+    }
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> RoomHandle<'a> {
+    pub fn id(&self) -> RoomId {
+        self.id
+    }
+
+    pub fn publish_summary(&self, mut summary: RoomSummary) {
+        summary.id = self.id;
+        let mut rooms = self.rooms.rooms.write().expect("rooms lock");
+        match rooms.get_mut(&self.id) {
+            Some(room) => room.summary = summary,
+            None => {
+                rooms.insert(self.id, Room { summary: summary, state: None });
+            }
+        }
+    }
+
+    // Only takes effect once the room has a summary
+    pub fn publish_state(&self, state: Option<Arc<MetroModel>>) {
+        if let Some(room) = self.rooms.rooms.write().expect("rooms lock").get_mut(&self.id) {
+            room.state = state;
+        }
+    }
+}
+
+impl<'a> Drop for RoomHandle<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut rooms) = self.rooms.rooms.write() {
+            rooms.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(phase: &str) -> RoomSummary {
+        RoomSummary {
+            id: 0,
+            phase: phase.to_string(),
+            players: 1,
+            bots: 0,
+            map: "Test".to_string(),
+            scoring: "LastMile".to_string(),
+            visibility: "Full".to_string(),
+            ruleset: "Competitive".to_string(),
+            week: 0,
+            tick: 0,
+            scores: None,
+            team_scores: None,
+        }
+    }
+
+    #[test]
+    fn rooms_come_and_go_with_their_games() {
+        let rooms = Rooms::new();
+        let first = rooms.open();
+        let second = rooms.open();
+        assert_eq!(0, rooms.count());
+
+        first.publish_summary(summary("lobby"));
+        second.publish_state(Some(Arc::new(MetroModel::new())));
+        assert_eq!(None, rooms.state(second.id()));
+        second.publish_summary(summary("game"));
+        second.publish_state(Some(Arc::new(MetroModel::new())));
+        assert_eq!(vec![0, 1], rooms.list().iter().map(|r| r.id).collect::<Vec<_>>());
+        assert_eq!(Some("game".to_string()), rooms.summary(second.id()).map(|s| s.phase));
+        assert_eq!(Some(None), rooms.state(first.id()));
+        assert!(rooms.state(second.id()).unwrap().is_some());
+
+        drop(first);
+        assert_eq!(1, rooms.count());
+        assert_eq!(None, rooms.summary(0));
+        assert_eq!(None, rooms.state(0));
+    }
+}
@@ -0,0 +1,157 @@
+use metro_game::{ Delivery, MetroModel, PlayerAction };
+use player_id::PlayerId;
+use randoms::Random;
+use results::GameEnd;
+use scoring::ScoringPolicy;
+use spawning::Spawner;
+
+// How many new lines a week brings and how many any one side may hold
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineAllowance {
+    pub per_week: u8,
+    pub max: u8,
+}
+
+// The rules a game is played by, asked at each point where they could
+// differ. The game itself only moves trains and passengers around.
+pub trait Ruleset {
+    fn name(&self) -> &'static str;
+
+    // Whether everyone builds on the same lines, whoever started them
+    fn shared_network(&self) -> bool {
+        false
+    }
+
+    // Called as each game starts, so nothing carries over from the last one
+    fn reset(&mut self) {}
+
+    // Called once per game tick, before the model moves. This is where new
+    // stations and passengers come from.
+    fn on_tick(&mut self, model: &mut MetroModel, spawner: &mut Spawner, random: &dyn Random) {
+        spawner.spawn(model, random);
+    }
+
+    fn on_delivery(&mut self, model: &mut MetroModel, delivery: &Delivery, scoring: &dyn ScoringPolicy);
+
+    // Who gets a new line this weekend, once for each line
+    fn on_week_end(&mut self, model: &MetroModel, players: &[PlayerId], allowance: LineAllowance) -> Vec<PlayerId> {
+        lines_by_side(model, players, allowance)
+    }
+
+    // Checked after every tick
+    fn is_game_over(&self, model: &MetroModel) -> Option<GameEnd> {
+        model.overcrowded_station().map(GameEnd::Overcrowded)
+    }
+
+    // Actions that aren't valid are dropped without a word
+    fn validate_action(&self, model: &MetroModel, player: &PlayerId, action: &PlayerAction) -> bool;
+
+    // What a player ends the game with
+    fn score(&self, model: &MetroModel, player: &PlayerId) -> f32 {
+        model.scores().get(player).cloned().unwrap_or(0.)
+    }
+}
+
+// Everyone for themselves, or for their team. Points go where the scoring
+// policy says, only a line's owner or their teammates can build on it and
+// the first overcrowded station ends the game.
+pub struct Competitive;
+
+impl Ruleset for Competitive {
+    fn name(&self) -> &'static str {
+        "Competitive"
+    }
+
+    fn on_delivery(&mut self, model: &mut MetroModel, delivery: &Delivery, scoring: &dyn ScoringPolicy) {
+        for (player, points) in scoring.split(delivery.passenger.legs(), delivery.points) {
+            model.add_score(player, points);
+        }
+    }
+
+    fn validate_action(&self, model: &MetroModel, player: &PlayerId, action: &PlayerAction) -> bool {
+        match *action {
+            PlayerAction::InsertAtLineBeginning(ref line, _) | PlayerAction::InsertAtLineEnd(ref line, _) => model.can_edit(player, line),
+            _ => true,
+        }
+    }
+}
+
+// Everyone builds one network together. Anyone can build on any line, the
+// line cap is for everyone at once and the score is everything delivered.
+pub struct Coop;
+
+impl Ruleset for Coop {
+    fn name(&self) -> &'static str {
+        "Coop"
+    }
+
+    fn shared_network(&self) -> bool {
+        true
+    }
+
+    // Deliveries are already counted in the model's stats
+    fn on_delivery(&mut self, _model: &mut MetroModel, _delivery: &Delivery, _scoring: &dyn ScoringPolicy) {}
+
+    fn validate_action(&self, _model: &MetroModel, _player: &PlayerId, _action: &PlayerAction) -> bool {
+        true
+    }
+
+    fn score(&self, model: &MetroModel, _player: &PlayerId) -> f32 {
+        model.total_delivered() as f32
+    }
+}
+
+// Players who share lines count them together and get their new ones once,
+// through whichever of them holds the fewest
+pub fn lines_by_side(model: &MetroModel, players: &[PlayerId], allowance: LineAllowance) -> Vec<PlayerId> {
+    let owned = |id: &PlayerId| model.lines().iter().filter(|l| l.owner() == id).count();
+    let mut given = Vec::new();
+    for player in players.iter() {
+        let fewest = players.iter()
+            .filter(|id| model.same_side(id, player))
+            .min_by_key(|id| owned(id));
+        if fewest != Some(player) {
+            continue;
+        }
+        let held = model.lines().iter().filter(|l| model.same_side(l.owner(), player)).count();
+        let room = (allowance.max as usize).saturating_sub(held);
+        given.extend(vec![*player; room.min(allowance.per_week as usize)]);
+    }
+    given
+}
+
+pub fn all_rulesets() -> Vec<Box<dyn Ruleset + Send>> {
+    vec![
+        Box::new(Competitive),
+        Box::new(Coop),
+    ]
+}
+
+pub fn ruleset_by_name(name: &str) -> Option<Box<dyn Ruleset + Send>> {
+    all_rulesets().into_iter().find(|r| r.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metro_game::StationId;
+
+    #[test]
+    fn competitors_keep_to_their_own_lines() {
+        let mut model = MetroModel::new();
+        let line = model.add_line(PlayerId::new(1), (0., 0., 0.));
+        let insert = PlayerAction::InsertAtLineEnd(line, StationId(0));
+        assert!(Competitive.validate_action(&model, &PlayerId::new(1), &insert));
+        assert!(!Competitive.validate_action(&model, &PlayerId::new(2), &insert));
+        assert!(Coop.validate_action(&model, &PlayerId::new(2), &insert));
+    }
+
+    #[test]
+    fn weekly_lines_stop_at_the_cap() {
+        let mut model = MetroModel::new();
+        model.add_line(PlayerId::new(1), (0., 0., 0.));
+        let players = vec![PlayerId::new(1), PlayerId::new(2)];
+        let allowance = LineAllowance { per_week: 2, max: 2 };
+        assert_eq!(vec![PlayerId::new(1), PlayerId::new(2), PlayerId::new(2)], lines_by_side(&model, &players, allowance));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{ channel, Receiver };
+
+use serde_json;
+
+use events::InputEvent;
+use game::{ Game, SteppedGame };
+use metro_game::{ LineId, MetroGame, MetroModel, PlayerAction, Point, StateUpdate, StationId, TrainId };
+use player::Player;
+use player_id::PlayerId;
+use randoms::Random;
+use ticks::NoWaitTicker;
+
+// Hands out the values queued by `Step::Random`, then 1 once they run out so
+// a scenario doesn't have to script every roll
+struct ScriptedRandom {
+    queued: Receiver<f64>,
+}
+
+impl Random for ScriptedRandom {
+    fn gen(&self) -> f64 {
+        self.queued.try_recv().unwrap_or(1f64)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum Step {
+    Connect(u16),
+    Disconnect(u16),
+    Act(u16, PlayerAction),
+    // Values handed out by the game's random source, in order. Once they run
+    // out every roll comes up 1, so nothing spawns unless asked to.
+    Random(Vec<f64>),
+    Tick(u32),
+    Expect(Expectation),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum Expectation {
+    Tick(u64),
+    Week(u32),
+    StationCount(usize),
+    StationPassengers(StationId, usize),
+    TrainCount(usize),
+    TrainPosition(TrainId, Point),
+    TrainPassengers(TrainId, usize),
+    LineStations(LineId, Vec<StationId>),
+    Score(u16, f32),
+    Delivered(u16, u32),
+    LobbyCount(u16, u16),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScenarioFailure {
+    pub step: usize,
+    pub description: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ScenarioFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Scenario failed at step {}: {}", self.step, self.description)?;
+        writeln!(f, "- expected: {}", self.expected)?;
+        write!(f, "+ actual:   {}", self.actual)
+    }
+}
+
+impl Scenario {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Scenario { steps: steps }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("Could not parse scenario: {}", e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{} ({})", e, path.display()))
+    }
+
+    // Runs every step on the current thread. Connections and actions are
+    // held until the next tick, the same as they would be from a socket.
+    pub fn run(&self) -> Result<(), ScenarioFailure> {
+        let (_, from_players) = channel();
+        let (to_random, random) = channel();
+        let ticker = NoWaitTicker { tick_length: 1. / 30. };
+        let mut game = MetroGame::new(from_players, ticker, ScriptedRandom { queued: random });
+        let mut events = Vec::new();
+        let mut lobby_counts: HashMap<PlayerId, u16> = HashMap::new();
+
+        for (i, step) in self.steps.iter().enumerate() {
+            match *step {
+                Step::Connect(id) => {
+                    events.push(InputEvent::Connection(PlayerId::new(id), Player::detached()));
+                }
+                Step::Disconnect(id) => {
+                    events.push(InputEvent::Disconnection(PlayerId::new(id)));
+                }
+                Step::Act(id, ref action) => {
+                    events.push(InputEvent::PlayerAction(PlayerId::new(id), action.clone()));
+                }
+                Step::Random(ref values) => {
+                    for v in values.iter() {
+                        to_random.send(*v).expect("scenario random");
+                    }
+                }
+                Step::Tick(n) => {
+                    for _ in 0..n {
+                        for (id, update) in game.step(mem::take(&mut events)) {
+                            if let StateUpdate::LobbyCount(count) = update {
+                                lobby_counts.insert(id, count);
+                            }
+                        }
+                    }
+                }
+                Step::Expect(ref expectation) => {
+                    check(expectation, game.model(), &lobby_counts).map_err(|(expected, actual)| ScenarioFailure {
+                        step: i,
+                        description: format!("{:?}", expectation),
+                        expected: expected,
+                        actual: actual,
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn compare<T: fmt::Debug + PartialEq>(expected: T, actual: T) -> Result<(), (String, String)> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err((format!("{:?}", expected), format!("{:?}", actual)))
+    }
+}
+
+fn check(expectation: &Expectation, model: &MetroModel, lobby_counts: &HashMap<PlayerId, u16>) -> Result<(), (String, String)> {
+    match *expectation {
+        Expectation::Tick(tick) => compare(tick, model.tick()),
+        Expectation::Week(week) => compare(week, model.week()),
+        Expectation::StationCount(count) => compare(count, model.station_count()),
+        Expectation::StationPassengers(ref id, count) =>
+            compare(Some(count), model.get_station(id).map(|s| s.passenger_count())),
+        Expectation::TrainCount(count) => compare(count, model.trains().len()),
+        Expectation::TrainPosition(ref id, (x, y)) => {
+            let actual = model.get_train(id).map(|t| t.position());
+            match actual {
+                // Trains move in float steps, so allow for rounding
+                Some((ax, ay)) if (ax - x).abs() < 0.01 && (ay - y).abs() < 0.01 => Ok(()),
+                _ => Err((format!("{:?}", Some((x, y))), format!("{:?}", actual))),
+            }
+        }
+        Expectation::TrainPassengers(ref id, count) =>
+            compare(Some(count), model.get_train(id).map(|t| t.passenger_count())),
+        Expectation::LineStations(ref id, ref stations) =>
+            compare(Some(stations.clone()), model.get_line(id).map(|l| l.all_stations().into_iter().cloned().collect())),
+        Expectation::Score(player, score) =>
+            compare(score, model.scores().get(&PlayerId::new(player)).cloned().unwrap_or(0.)),
+        Expectation::Delivered(player, delivered) =>
+            compare(delivered, model.delivery_stats().get(&PlayerId::new(player)).map_or(0, |d| d.delivered)),
+        Expectation::LobbyCount(player, count) => compare(Some(count), lobby_counts.get(&PlayerId::new(player)).cloned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_line_scenario() {
+        let scenario = Scenario::load(Path::new("scenarios/first_line.json")).unwrap();
+        if let Err(failure) = scenario.run() {
+            panic!("{}", failure);
+        }
+    }
+
+    #[test]
+    fn failures_show_expected_and_actual() {
+        let scenario = Scenario::new(vec![
+            Step::Connect(0),
+            Step::Tick(2),
+            Step::Expect(Expectation::LobbyCount(0, 1)),
+            Step::Expect(Expectation::StationPassengers(StationId(0), 3)),
+        ]);
+        let failure = scenario.run().unwrap_err();
+        assert_eq!(3, failure.step);
+        assert_eq!(
+            "Scenario failed at step 3: StationPassengers(StationId(0), 3)\n- expected: Some(3)\n+ actual:   None",
+            failure.to_string());
+    }
+}
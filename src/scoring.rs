@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use metro_game::JourneyLeg;
+use player_id::PlayerId;
+
+// Decides who gets the points for a delivered passenger, given the legs of
+// their journey in the order they were ridden.
+pub trait ScoringPolicy {
+    fn name(&self) -> &'static str;
+    fn split(&self, legs: &[JourneyLeg], points: f32) -> Vec<(PlayerId, f32)>;
+}
+
+// Everything goes to whoever dropped the passenger at their destination.
+pub struct LastMile;
+
+impl ScoringPolicy for LastMile {
+    fn name(&self) -> &'static str {
+        "LastMile"
+    }
+
+    fn split(&self, legs: &[JourneyLeg], points: f32) -> Vec<(PlayerId, f32)> {
+        legs.last().map(|l| vec![(l.player, points)]).unwrap_or_default()
+    }
+}
+
+// Points are split by how far each player carried the passenger.
+pub struct ProportionalDistance;
+
+impl ScoringPolicy for ProportionalDistance {
+    fn name(&self) -> &'static str {
+        "ProportionalDistance"
+    }
+
+    fn split(&self, legs: &[JourneyLeg], points: f32) -> Vec<(PlayerId, f32)> {
+        let total: f32 = legs.iter().map(|l| l.distance).sum();
+        if total <= 0. {
+            return LastMile.split(legs, points);
+        }
+        let mut by_player = Vec::new();
+        for (player, distance) in group_by_player(legs, |l| l.distance) {
+            by_player.push((player, points * distance / total));
+        }
+        by_player
+    }
+}
+
+// Every player who carried the passenger at all gets an equal share.
+pub struct SharedEqually;
+
+impl ScoringPolicy for SharedEqually {
+    fn name(&self) -> &'static str {
+        "SharedEqually"
+    }
+
+    fn split(&self, legs: &[JourneyLeg], points: f32) -> Vec<(PlayerId, f32)> {
+        let players = group_by_player(legs, |_| 0.);
+        let share = points / players.len() as f32;
+        players.into_iter().map(|(p, _)| (p, share)).collect()
+    }
+}
+
+// Sums `f` over each player's legs, keeping players in the order they first
+// carried the passenger.
+fn group_by_player<F: Fn(&JourneyLeg) -> f32>(legs: &[JourneyLeg], f: F) -> Vec<(PlayerId, f32)> {
+    let mut order = Vec::new();
+    let mut totals = HashMap::new();
+    for l in legs.iter() {
+        if !totals.contains_key(&l.player) {
+            order.push(l.player);
+        }
+        *totals.entry(l.player).or_insert(0.) += f(l);
+    }
+    order.into_iter().map(|p| (p, totals[&p])).collect()
+}
+
+pub fn all_policies() -> Vec<Box<dyn ScoringPolicy + Send>> {
+    vec![
+        Box::new(LastMile),
+        Box::new(ProportionalDistance),
+        Box::new(SharedEqually),
+    ]
+}
+
+pub fn policy_by_name(name: &str) -> Option<Box<dyn ScoringPolicy + Send>> {
+    all_policies().into_iter().find(|p| p.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metro_game::LineId;
+
+    fn leg(player: u16, line: usize, distance: f32) -> JourneyLeg {
+        JourneyLeg { line: LineId(line), player: PlayerId::new(player), distance: distance }
+    }
+
+    #[test]
+    fn splitting_a_three_leg_journey() {
+        let legs = vec![ leg(1, 0, 300.), leg(2, 1, 50.), leg(1, 2, 150.), leg(3, 3, 0.) ];
+
+        assert_eq!(vec![(PlayerId::new(3), 2.)], LastMile.split(&legs, 2.));
+        assert_eq!(
+            vec![(PlayerId::new(1), 1.8), (PlayerId::new(2), 0.2), (PlayerId::new(3), 0.)],
+            ProportionalDistance.split(&legs, 2.));
+        assert_eq!(
+            vec![(PlayerId::new(1), 1.), (PlayerId::new(2), 1.), (PlayerId::new(3), 1.)],
+            SharedEqually.split(&legs, 3.));
+    }
+
+    #[test]
+    fn journeys_without_distance() {
+        let legs = vec![ leg(1, 0, 0.), leg(2, 1, 0.) ];
+        assert_eq!(vec![(PlayerId::new(2), 1.)], ProportionalDistance.split(&legs, 1.));
+        assert_eq!(Vec::<(PlayerId, f32)>::new(), SharedEqually.split(&[], 1.));
+        assert_eq!(Vec::<(PlayerId, f32)>::new(), LastMile.split(&[], 1.));
+    }
+}
@@ -1,30 +1,34 @@
-use std::net::TcpListener;
-use std::sync::mpsc::channel;
+use std::net::{ SocketAddr, TcpListener };
+use std::sync::mpsc::{ channel, Receiver };
 use std::thread;
 use std::time::Duration;
 
+use events::InputEvent;
 use game::Game;
 use player::*;
 use ticks::*;
 use randoms::*;
 
-pub fn listen<G: Game<TPSTicker, RealRandom>>(websocket_address: String, murder_host: String) {
+// Runs the game `make_game` makes, taking players on `address`. Other HTTP
+// requests there go to the web frontend at `web`, if there is one.
+pub fn listen<G, F>(address: String, murder_host: String, web: Option<SocketAddr>, settings: ConnectionSettings, make_game: F)
+where G: Game<TPSTicker, SeededRandom>, F: FnOnce(Receiver<InputEvent>, TPSTicker, SeededRandom) -> G {
     let (connection_sender, connection_receiver) = channel();
     let (to_server_sender, to_server_receiver) = channel();
     thread::spawn(move || {
-        let tcp = TcpListener::bind(websocket_address).unwrap();
-        connection_handler(connection_sender, tcp, murder_host);
-        println!("Closing connection handler");
+        let tcp = TcpListener::bind(address).unwrap();
+        connection_handler(connection_sender, tcp, murder_host, settings.max_connections_per_ip, web);
+        info!("Closing connection handler");
     });
     thread::spawn(move || {
-        player_handler(connection_receiver, to_server_sender);
-        println!("Closing player handler");
+        player_handler(connection_receiver, to_server_sender, settings);
+        info!("Closing player handler");
     });
     let tick_rate = Duration::from_millis(1000/30);
     let ticker = TPSTicker::new(tick_rate);
-    let random = RealRandom;
-    G::new(to_server_receiver, ticker, random).main();
-    println!("Game exiting");
+    let random = SeededRandom::fresh();
+    make_game(to_server_receiver, ticker, random).main();
+    info!("Game exiting");
 }
 
 
@@ -35,9 +39,10 @@ mod tests {
     use super::*;
     use events::*;
     use metro_game::*;
+    use protocol::{ self, Encoding };
+    use std::io::{ Read, Write };
     use std::net::TcpStream;
-    use std::sync::mpsc::Receiver;
-    use std::time::{Duration};
+    use std::time::Instant;
     use self::url::Url;
     use tungstenite;
 
@@ -57,35 +62,203 @@ mod tests {
             loop {
                 match self.r.recv().unwrap() {
                     InputEvent::Connection(_, player) => { p = Some(player); },
-                    InputEvent::PlayerAction(_, _) => { p.take().map(|player| player.send_message(StateUpdate::LobbyCount(1))); },
+                    InputEvent::PlayerAction(_, _) => {
+                        if let Some(ref player) = p {
+                            let _ = player.send_message(StateUpdate::LobbyCount(1));
+                        }
+                    },
                     _ => {},
                 }
             }
         }
     }
 
+    // Binding here rather than in a spawned `listen` means the port is ready
+    // before we connect, and port 0 keeps parallel runs apart
+    fn start_echo_server(settings: ConnectionSettings) -> (SocketAddr, thread::JoinHandle<()>) {
+        start_echo_server_on(settings, "127.0.0.1", None)
+    }
+
+    fn start_echo_server_on(settings: ConnectionSettings, murder_host: &str, web: Option<SocketAddr>) -> (SocketAddr, thread::JoinHandle<()>) {
+        let murder_host = murder_host.to_string();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let (connection_sender, connection_receiver) = channel();
+        let (to_game, from_players) = channel();
+        let handler = thread::spawn(move || connection_handler(connection_sender, tcp, murder_host, settings.max_connections_per_ip, web));
+        thread::spawn(move || player_handler(connection_receiver, to_game, settings));
+        thread::spawn(move || {
+            let mut game: EchoGame = Game::<NoWaitTicker, Always1Random>::new(
+                from_players, NoWaitTicker { tick_length: 1. }, Always1Random);
+            Game::<NoWaitTicker, Always1Random>::main(&mut game);
+        });
+        (address, handler)
+    }
+
     #[test]
     fn server_comms1() {
+        let (address, handler) = start_echo_server(ConnectionSettings::default());
+        let uri = Url::parse(&format!("ws://{}/ws", address)).unwrap();
+        let (mut ws, _resp) = tungstenite::connect(uri.clone()).unwrap();
+        let hello = "{\"Hello\":{\"protocol_version\":2,\"client_name\":\"test\"}}";
+        assert!(ws.write_message(tungstenite::Message::text(hello.to_string())).is_ok());
+        assert_eq!("{\"Welcome\":{\"protocol_version\":2,\"player\":0,\"encoding\":\"Json\"}}", ws.read_message().unwrap().to_text().unwrap());
+        assert!(ws.write_message(tungstenite::Message::text("{\"StartGame\":null}".to_string())).is_ok());
 
-        thread::spawn(||
-            listen::<EchoGame>("127.0.0.1:12345".to_string(), "127.0.0.1".to_string())
-        );
+        assert_eq!("{\"LobbyCount\":1}", ws.read_message().unwrap().to_text().unwrap());
 
-        let mut ws = {
-            let uri = Url::parse("ws://127.0.0.1:12345").unwrap();
-            loop {
-                if let Ok((mut ws, _resp)) = tungstenite::connect(uri.clone()) {
-                    break ws;
-                }
+        let (mut old_ws, _resp) = tungstenite::connect(uri).unwrap();
+        let old_hello = "{\"Hello\":{\"protocol_version\":1,\"client_name\":\"old\"}}";
+        assert!(old_ws.write_message(tungstenite::Message::text(old_hello.to_string())).is_ok());
+        assert_eq!(
+            "{\"Reject\":{\"reason\":\"old speaks protocol version 1 but this server speaks version 2\"}}",
+            old_ws.read_message().unwrap().to_text().unwrap());
+
+        // A plain connection from the murder host shuts the handler down,
+        // closing the listener with it
+        drop(TcpStream::connect(address).unwrap());
+        handler.join().unwrap();
+
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn message_pack_comms() {
+        let (address, _) = start_echo_server(ConnectionSettings::default());
+        let uri = Url::parse(&format!("ws://{}/ws", address)).unwrap();
+        let (mut ws, _resp) = tungstenite::connect(uri).unwrap();
+        let hello = "{\"Hello\":{\"protocol_version\":2,\"client_name\":\"test\",\"encoding\":\"MessagePack\"}}";
+        assert!(ws.write_message(tungstenite::Message::text(hello.to_string())).is_ok());
+        assert_eq!(
+            "{\"Welcome\":{\"protocol_version\":2,\"player\":0,\"encoding\":\"MessagePack\"}}",
+            ws.read_message().unwrap().to_text().unwrap());
+
+        let start = protocol::encode(&PlayerAction::StartGame, Encoding::MessagePack);
+        assert!(ws.write_message(tungstenite::Message::binary(start)).is_ok());
+        match ws.read_message().unwrap() {
+            tungstenite::Message::Binary(bytes) => {
+                assert_eq!(protocol::encode(&StateUpdate::LobbyCount(1), Encoding::MessagePack), bytes);
             }
-        };
-        assert!(ws.write_message(tungstenite::Message::text("{\"StartGame\":null}".to_string())).is_ok());
+            other => panic!("Expected a binary frame, got {:?}", other),
+        }
+    }
 
-        assert_eq!("{\"LobbyCount\":1}", ws.read_message().unwrap().to_text().unwrap());
+    // Reads until the server hangs up, counting the pings on the way
+    fn pings_before_hang_up(ws: &mut tungstenite::WebSocket<tungstenite::client::AutoStream>) -> usize {
+        let mut pings = 0;
+        loop {
+            match ws.read_message() {
+                Ok(tungstenite::Message::Ping(_)) => pings += 1,
+                Ok(other) => panic!("Expected only pings, got {:?}", other),
+                Err(_) => return pings,
+            }
+        }
+    }
 
-        TcpStream::connect("127.0.0.1:12345").unwrap();
+    fn say_hello(address: SocketAddr) -> tungstenite::WebSocket<tungstenite::client::AutoStream> {
+        let uri = Url::parse(&format!("ws://{}/ws", address)).unwrap();
+        let (mut ws, _resp) = tungstenite::connect(uri).unwrap();
+        let hello = "{\"Hello\":{\"protocol_version\":2,\"client_name\":\"test\"}}";
+        assert!(ws.write_message(tungstenite::Message::text(hello.to_string())).is_ok());
+        assert!(ws.read_message().unwrap().to_text().unwrap().starts_with("{\"Welcome\""));
+        ws
+    }
+
+    #[test]
+    fn idle_clients_are_dropped() {
+        let idle_timeout = Duration::from_millis(300);
+        let (address, _) = start_echo_server(ConnectionSettings {
+            ping_interval: Duration::from_millis(20),
+            idle_timeout: idle_timeout,
+            ..ConnectionSettings::default()
+        });
+        let connected = Instant::now();
+        let mut ws = say_hello(address);
+        // Reading answers the pings, so only the idle timeout can end this
+        assert!(pings_before_hang_up(&mut ws) > 0);
+        assert!(connected.elapsed() >= idle_timeout);
+    }
+
+    #[test]
+    fn clients_that_stop_answering_pings_are_dropped() {
+        let (address, _) = start_echo_server(ConnectionSettings {
+            ping_interval: Duration::from_millis(20),
+            missed_pongs: 2,
+            ..ConnectionSettings::default()
+        });
+        let mut ws = say_hello(address);
+        // Not reading means not answering
         thread::sleep(Duration::from_millis(100));
+        assert_eq!(2, pings_before_hang_up(&mut ws));
+    }
+
+    // Everything the server sends before hanging up, pings aside
+    fn messages_before_hang_up(ws: &mut tungstenite::WebSocket<tungstenite::client::AutoStream>) -> Vec<String> {
+        let mut messages = Vec::new();
+        loop {
+            match ws.read_message() {
+                Ok(tungstenite::Message::Text(text)) => messages.push(text),
+                Ok(_) => {}
+                Err(_) => return messages,
+            }
+        }
+    }
+
+    #[test]
+    fn floods_are_throttled_then_dropped() {
+        let (address, _) = start_echo_server(ConnectionSettings {
+            action_burst: 2.,
+            actions_per_second: 0.,
+            throttle_strikes: 1.,
+            ..ConnectionSettings::default()
+        });
+        let mut ws = say_hello(address);
+        for _ in 0..4 {
+            assert!(ws.write_message(tungstenite::Message::text("\"StartGame\"".to_string())).is_ok());
+        }
+        let messages = messages_before_hang_up(&mut ws);
+        assert!(messages.contains(&"{\"LobbyCount\":1}".to_string()));
+        assert_eq!(Some(&"{\"Warning\":\"Too many actions, disconnecting\"}".to_string()), messages.last());
+
+        let mut ws = say_hello(address);
+        assert!(ws.write_message(tungstenite::Message::text(" ".repeat(5000))).is_ok());
+        assert_eq!(
+            vec!["{\"Warning\":\"Message of 5000 bytes is over the 4096 byte limit, disconnecting\"}".to_string()],
+            messages_before_hang_up(&mut ws));
+    }
+
+    #[test]
+    fn connections_per_ip_are_limited() {
+        let (address, _) = start_echo_server(ConnectionSettings {
+            max_connections_per_ip: 1,
+            ..ConnectionSettings::default()
+        });
+        let _first = say_hello(address);
+        let uri = Url::parse(&format!("ws://{}/ws", address)).unwrap();
+        assert!(tungstenite::connect(uri).is_err());
+    }
+
+    #[test]
+    fn web_requests_share_the_port() {
+        // Stands in for the web frontend, answering one request
+        let web = TcpListener::bind("127.0.0.1:0").unwrap();
+        let web_address = web.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = web.accept().unwrap();
+            let mut head = [0; 1024];
+            let n = stream.read(&mut head).unwrap();
+            assert!(String::from_utf8_lossy(&head[..n]).starts_with("GET /metrics HTTP/1.1\r\n"));
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").unwrap();
+        });
+        let (address, _) = start_echo_server_on(ConnectionSettings::default(), "", Some(web_address));
+
+        let mut http = TcpStream::connect(address).unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
 
-        assert!(TcpStream::connect("127.0.0.1:12345").is_err());
+        say_hello(address);
     }
 }
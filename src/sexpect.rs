@@ -1,4 +1,5 @@
 use std::fmt::Debug;
+use std::panic::Location;
 
 pub trait SoftExpect<E> {
     fn sexpect(self, message: &str);
@@ -6,9 +7,12 @@ pub trait SoftExpect<E> {
 
 impl<E> SoftExpect<E> for Result<(), E> 
   where E: Debug {
+    // Logged from where it was called, since that's more use than here
+    #[track_caller]
     fn sexpect(self, message: &str) {
         if let Err(e) = self {
-            println!("{:?}: {:?}", message, e);
+            let caller = Location::caller();
+            warn!(location:% = caller, error:? = e; "{}", message);
         }
     }
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::time::{ Duration, Instant };
+
+use serde_json;
+
+use events::InputEvent;
+use game::{ Game, SteppedGame };
+use maps::MapDefinition;
+use metro_game::{ DeliveryStats, MetroGame, MetroModel, PlayerAction };
+use player::Player;
+use player_id::PlayerId;
+use randoms::SeededRandom;
+use results::GameEnd;
+use ticks::NoWaitTicker;
+
+#[derive(Debug, Deserialize)]
+pub struct ScriptedAction {
+    pub tick: u64,
+    pub player: u16,
+    pub action: PlayerAction,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimSettings {
+    pub ticks: u64,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_players")]
+    pub players: u16,
+    #[serde(default)]
+    pub map: Option<String>,
+    #[serde(default)]
+    pub scoring: Option<String>,
+    #[serde(default)]
+    pub visibility: Option<String>,
+    #[serde(default)]
+    pub ruleset: Option<String>,
+    #[serde(default)]
+    pub bots: Vec<String>,
+    #[serde(default)]
+    pub actions: Vec<ScriptedAction>,
+}
+
+fn default_players() -> u16 {
+    1
+}
+
+impl SimSettings {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        serde_json::from_str(&json)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    }
+}
+
+#[derive(Debug)]
+pub struct SimResult {
+    pub ticks: u64,
+    pub elapsed: Duration,
+    pub weeks: u32,
+    pub scores: HashMap<PlayerId, f32>,
+    pub deliveries: HashMap<PlayerId, DeliveryStats>,
+    pub stations: usize,
+    pub blow_times: Vec<u32>,
+    // Why the game ended, if it didn't last all the ticks asked for
+    pub ended: Option<GameEnd>,
+    pub model: MetroModel,
+}
+
+impl SimResult {
+    pub fn ticks_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1_000_000_000_f64;
+        if secs == 0. { 0. } else { self.ticks as f64 / secs }
+    }
+}
+
+// Runs a whole game on the current thread without waiting between ticks.
+// Player 0 picks the map, scoring, visibility and ruleset, adds any bots and starts the game on the
+// first tick, then the scripted actions are fed in on the ticks they ask for.
+pub fn run(settings: &SimSettings, maps: Vec<MapDefinition>) -> SimResult {
+    let (_, from_players) = channel();
+    let ticker = NoWaitTicker { tick_length: 1. / 30. };
+    let mut game = MetroGame::new(from_players, ticker, SeededRandom::new(settings.seed));
+    game.set_maps(maps);
+
+    let mut events = Vec::new();
+    for i in 0..settings.players {
+        events.push(InputEvent::Connection(PlayerId::new(i), Player::detached()));
+    }
+    let host = PlayerId::new(0);
+    if let Some(ref map) = settings.map {
+        events.push(InputEvent::PlayerAction(host, PlayerAction::SelectMap(map.clone())));
+    }
+    if let Some(ref scoring) = settings.scoring {
+        events.push(InputEvent::PlayerAction(host, PlayerAction::SelectScoring(scoring.clone())));
+    }
+    if let Some(ref visibility) = settings.visibility {
+        events.push(InputEvent::PlayerAction(host, PlayerAction::SelectVisibility(visibility.clone())));
+    }
+    if let Some(ref ruleset) = settings.ruleset {
+        events.push(InputEvent::PlayerAction(host, PlayerAction::SelectRuleset(ruleset.clone())));
+    }
+    for bot in settings.bots.iter() {
+        events.push(InputEvent::PlayerAction(host, PlayerAction::AddBot(bot.clone())));
+    }
+    events.push(InputEvent::PlayerAction(host, PlayerAction::StartGame));
+
+    let mut actions: Vec<&ScriptedAction> = settings.actions.iter().collect();
+    actions.sort_by_key(|a| a.tick);
+    let mut next_action = 0;
+
+    let start = Instant::now();
+    let mut ticks = settings.ticks;
+    let mut ended = None;
+    for tick in 0..settings.ticks {
+        while next_action < actions.len() && actions[next_action].tick <= tick {
+            let a = actions[next_action];
+            events.push(InputEvent::PlayerAction(PlayerId::new(a.player), a.action.clone()));
+            next_action += 1;
+        }
+        game.step(mem::take(&mut events));
+        if let Some(result) = game.take_result() {
+            ticks = tick + 1;
+            ended = Some(result.ended);
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let model = game.model();
+    SimResult {
+        ticks: ticks,
+        elapsed: elapsed,
+        weeks: model.week(),
+        scores: model.scores().clone(),
+        deliveries: model.delivery_stats().clone(),
+        stations: model.station_count(),
+        blow_times: model.blow_times(),
+        ended: ended,
+        model: model.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maps;
+    use metro_game::{ LineId, StationId };
+
+    fn settings(seed: u64) -> SimSettings {
+        SimSettings {
+            ticks: 6000,
+            seed: seed,
+            players: 2,
+            map: Some("Classic".to_string()),
+            scoring: None,
+            visibility: None,
+            ruleset: None,
+            bots: Vec::new(),
+            actions: vec![
+                ScriptedAction { tick: 5, player: 0, action: PlayerAction::NewLine(StationId(0), StationId(1)) },
+                ScriptedAction { tick: 6, player: 0, action: PlayerAction::InsertAtLineEnd(LineId(0), StationId(2)) },
+            ],
+        }
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let first = run(&settings(7), maps::builtin_maps());
+        let second = run(&settings(7), maps::builtin_maps());
+        assert_eq!(6000, first.ticks);
+        assert_eq!(1, first.weeks);
+        assert!(first.stations > 3);
+        assert_eq!(first.stations, second.stations);
+        assert_eq!(first.blow_times, second.blow_times);
+        assert_eq!(first.scores, second.scores);
+    }
+
+    #[test]
+    fn bots_play_without_help() {
+        let mut settings = settings(3);
+        settings.players = 1;
+        settings.actions.clear();
+        settings.bots = vec!["Greedy".to_string(), "Greedy".to_string()];
+        let result = run(&settings, maps::builtin_maps());
+        let bots = [PlayerId::new(u16::MAX), PlayerId::new(u16::MAX - 1)];
+        for bot in bots.iter() {
+            assert!(result.deliveries.get(bot).map_or(0, |d| d.delivered) > 0, "{:?} delivered nothing: {:?}", bot, result);
+        }
+        assert!(!result.scores.contains_key(&PlayerId::new(0)));
+    }
+}
@@ -0,0 +1,69 @@
+extern crate metrolti_lib;
+extern crate log;
+
+use log::LevelFilter;
+
+use metrolti_lib::logging::{ self, LogSettings };
+use metrolti_lib::maps;
+use metrolti_lib::sim::{ self, SimSettings };
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("Usage: metrolti_sim <settings.json> [--seed N] [--ticks N]");
+    process::exit(2);
+}
+
+pub fn main() {
+    // A long sim has a lot of weekends, so only warnings unless asked
+    let mut log_settings = LogSettings::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    if env::var("METROLTI_LOG").is_err() {
+        log_settings.level = LevelFilter::Warn;
+    }
+    logging::init(log_settings).expect("logger");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = match args.first() {
+        Some(p) => p.clone(),
+        None => usage(),
+    };
+    let mut settings = match SimSettings::load(Path::new(&path)) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().and_then(|v| v.parse::<u64>().ok()).unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--seed" => settings.seed = value,
+            "--ticks" => settings.ticks = value,
+            _ => usage(),
+        }
+    }
+
+    let result = sim::run(&settings, maps::available_maps(Path::new("./maps/")));
+
+    println!("seed: {}", settings.seed);
+    println!("ticks: {} ({} weeks)", result.ticks, result.weeks);
+    if let Some(ref end) = result.ended {
+        println!("game over: {}", end);
+    }
+    println!("ticks/second: {:.0}", result.ticks_per_second());
+    println!("stations: {}", result.stations);
+    let max_blow = result.blow_times.iter().cloned().max().unwrap_or(0);
+    println!("blow times: {:?} (max {})", result.blow_times, max_blow);
+    let mut players: Vec<_> = result.scores.keys().cloned().collect();
+    players.sort();
+    for p in players {
+        let delivered = result.deliveries.get(&p).map(|d| d.delivered).unwrap_or(0);
+        println!("{:?}: score {:.1}, {} delivered", p, result.scores[&p], delivered);
+    }
+}
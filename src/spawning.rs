@@ -0,0 +1,88 @@
+use maps::SpawnWeight;
+use metro_game::{ MetroModel, StationId, StationType };
+use placement::StationPlacement;
+use randoms::Random;
+
+// Brings in new stations, and passengers at each station. Either gets more
+// likely every tick since the last one.
+pub struct Spawner {
+    pub placement: StationPlacement,
+    pub weights: Vec<SpawnWeight>,
+
+    ticks_since_last_station: u64,
+    min_ticks_between_stations: u64,
+    base_station_chance: f64,
+    station_chance_per_tick: f64,
+
+    ticks_since_last_passenger: Vec<u64>,
+    min_ticks_between_passengers: u64,
+    base_passenger_chance: f64,
+    passenger_chance_per_tick: f64,
+}
+
+impl Spawner {
+    pub fn new() -> Self {
+        Spawner {
+            placement: StationPlacement::new(),
+            weights: vec![
+                SpawnWeight { t: StationType::Circle, weight: 0.4 },
+                SpawnWeight { t: StationType::Square, weight: 0.3 },
+                SpawnWeight { t: StationType::Triangle, weight: 0.3 },
+            ],
+
+            ticks_since_last_station: 0,
+            min_ticks_between_stations: 30,
+            base_station_chance: 0.00005,
+            station_chance_per_tick: 0.000005,
+
+            ticks_since_last_passenger: Vec::new(),
+            min_ticks_between_passengers: 30,
+            base_passenger_chance: 0.00005,
+            passenger_chance_per_tick: 0.000005,
+        }
+    }
+
+    // A new game starts the waits over
+    pub fn reset(&mut self) {
+        self.ticks_since_last_station = 0;
+        self.ticks_since_last_passenger.clear();
+    }
+
+    pub fn spawn<R: Random + ?Sized>(&mut self, model: &mut MetroModel, random: &R) {
+        if let Some(spawnable_ticks) = self.ticks_since_last_station.checked_sub(self.min_ticks_between_stations) {
+            let chance = self.base_station_chance + self.station_chance_per_tick * spawnable_ticks as f64;
+            if random.gen() < chance {
+                if let Some(pos) = self.placement.find_position(model, random) {
+                    let station_type = self.random_station_type(random);
+                    model.add_station(station_type, pos);
+                }
+                self.ticks_since_last_station = 0;
+            }
+        }
+        self.ticks_since_last_station += 1;
+        self.ticks_since_last_passenger.resize(model.station_count(), 0);
+        for i in 0..model.station_count() {
+            if let Some(spawnable_ticks) = self.ticks_since_last_passenger[i].checked_sub(self.min_ticks_between_passengers) {
+                let chance = self.base_passenger_chance + self.passenger_chance_per_tick * spawnable_ticks as f64;
+                if random.gen() < chance {
+                    let station_type = self.random_station_type(random);
+                    model.add_passenger(&StationId(i), station_type);
+                    self.ticks_since_last_passenger[i] = 0;
+                }
+            }
+            self.ticks_since_last_passenger[i] += 1;
+        }
+    }
+
+    fn random_station_type<R: Random + ?Sized>(&self, random: &R) -> StationType {
+        let total: f64 = self.weights.iter().map(|w| w.weight).sum();
+        let mut roll = random.gen() * total;
+        for w in self.weights.iter() {
+            if roll < w.weight {
+                return w.t.clone();
+            }
+            roll -= w.weight;
+        }
+        self.weights.last().map(|w| w.t.clone()).unwrap_or(StationType::Circle)
+    }
+}
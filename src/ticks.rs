@@ -2,6 +2,8 @@ use std::sync::mpsc::{ Receiver, Sender };
 use std::time::{ Duration, Instant };
 use std::thread;
 
+use metrics::METRICS;
+
 pub trait Ticker {
     fn start(&mut self);
     fn wait_until_next_tick(&mut self) -> f64;
@@ -27,11 +29,14 @@ impl Ticker for TPSTicker {
     }
 
     fn wait_until_next_tick(&mut self) -> f64 {
-        let next_tick_wait = self.tps.checked_sub(self.last.elapsed());
+        let elapsed = self.last.elapsed();
+        let next_tick_wait = self.tps.checked_sub(elapsed);
+        METRICS.record_tick(elapsed, next_tick_wait.is_none());
         if let Some(wait) = next_tick_wait {
             thread::sleep(wait);
         } else {
-            println!("Server can't keep up");
+            let over = elapsed - self.tps;
+            warn!(over_ms = over.as_secs() * 1000 + over.subsec_millis() as u64; "Server can't keep up");
         }
         let time_waited = self.last.elapsed();
         self.last = Instant::now();
@@ -39,6 +44,19 @@ impl Ticker for TPSTicker {
     }
 }
 
+// Reports a fixed tick length without waiting, for running games as fast as
+// they can go.
+pub struct NoWaitTicker {
+    pub tick_length: f64,
+}
+
+impl Ticker for NoWaitTicker {
+    fn start(&mut self) {}
+    fn wait_until_next_tick(&mut self) -> f64 {
+        self.tick_length
+    }
+}
+
 pub struct TestTicker {
     pub r: Receiver<f64>,
     pub s: Sender<()>,
@@ -0,0 +1,36 @@
+// What each player is allowed to see of the game. Anything hidden from a
+// player is left out of the state sent to them, not just left undrawn.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Visibility {
+    Full,
+    // Players only see their own score and delivery stats
+    HiddenScores,
+    // Players only see passengers, trains and other players' track within
+    // `radius` of a station on one of their own lines. Stations themselves
+    // are always shown so there's somewhere to start a line.
+    FogOfWar { radius: f32 },
+}
+
+pub const DEFAULT_FOG_RADIUS: f32 = 150.;
+
+impl Visibility {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Visibility::Full => "Full",
+            Visibility::HiddenScores => "HiddenScores",
+            Visibility::FogOfWar { .. } => "FogOfWar",
+        }
+    }
+}
+
+pub fn all_visibilities() -> Vec<Visibility> {
+    vec![
+        Visibility::Full,
+        Visibility::HiddenScores,
+        Visibility::FogOfWar { radius: DEFAULT_FOG_RADIUS },
+    ]
+}
+
+pub fn visibility_by_name(name: &str) -> Option<Visibility> {
+    all_visibilities().into_iter().find(|v| v.name() == name)
+}
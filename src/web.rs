@@ -1,17 +1,26 @@
 use iron::prelude::*;
+use iron::Listening;
 use iron::status;
 use iron::middleware::Handler;
+use iron::mime::Mime;
+use serde::Serialize;
+use serde_json;
 use mount::Mount;
 use staticfile::Static;
 
 use handlebars_iron::*;
 
-pub fn startup_web_frontend(address: String, websocket_address: String, static_path: String) {
+use metrics::METRICS;
+use results::{ self, PlayerResult, RecordedResult, ResultsStore, Standing };
+use rooms::{ ROOMS, RoomId };
+
+// Starts the frontend on its own threads. Dropping the returned handle waits
+// on them, so hold on to it rather than letting it go.
+pub fn startup_web_frontend(address: String, static_path: String, results: ResultsStore) -> Listening {
     let data = WebData {
-        websocket: websocket_address.clone(),
         test: "Test".to_string(),
     };
-    let mut chain = Chain::new(H { data: data });
+    let mut chain = Chain::new(H { data: data, results: results.clone() });
 
     let mut hbse = HandlebarsEngine::new();
     hbse.add(Box::new(DirectorySource::new("./www/", ".hbs")));
@@ -25,23 +34,136 @@ pub fn startup_web_frontend(address: String, websocket_address: String, static_p
 
     let mut mount = Mount::new();
     mount.mount("/", chain)
+         .mount("/metrics", MetricsHandler)
+         .mount("/api/rooms", RoomsHandler)
+         .mount("/api/results", ResultsHandler { results: results })
          .mount("static", Static::new(static_path));
   
-    Iron::new(mount).http(address).unwrap();
+    Iron::new(mount).http(address).unwrap()
 }
 
 #[derive(Debug, Serialize)]
 struct WebData {
-    websocket: String,
     test: String,
 }
 
+#[derive(Debug, Serialize)]
+struct LeaderboardData {
+    standings: Vec<Standing>,
+    games: Vec<GameRow>,
+}
+
+#[derive(Debug, Serialize)]
+struct GameRow {
+    finished_at: u64,
+    map: String,
+    weeks: u32,
+    ended: String,
+    players: Vec<PlayerResult>,
+}
+
+// Recent games shown under the standings
+const LEADERBOARD_GAMES: usize = 20;
+
+impl LeaderboardData {
+    fn new(recorded: &[RecordedResult]) -> Self {
+        LeaderboardData {
+            standings: results::leaderboard(recorded),
+            games: recorded.iter().rev().take(LEADERBOARD_GAMES).map(|r| GameRow {
+                finished_at: r.finished_at,
+                map: r.result.map.clone(),
+                weeks: r.result.weeks,
+                ended: r.result.ended.to_string(),
+                players: r.result.players.clone(),
+            }).collect(),
+        }
+    }
+}
+
 struct H {
     data: WebData,
+    results: ResultsStore,
 }
 
 impl Handler for H {
-    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.url.path() == ["leaderboard"] {
+            let recorded = self.results.load().unwrap_or_else(|e| {
+                warn!(error = e.as_str(); "Could not load results");
+                Vec::new()
+            });
+            return Ok(Response::with((status::Ok, Template::new("leaderboard", LeaderboardData::new(&recorded)))));
+        }
         Ok(Response::with((status::Ok, Template::new("index", &self.data))))
     }
 }
+
+struct MetricsHandler;
+
+impl Handler for MetricsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let content_type: Mime = "text/plain; version=0.0.4".parse().expect("prometheus content type");
+        Ok(Response::with((status::Ok, content_type, METRICS.render())))
+    }
+}
+
+fn json<T: Serialize>(status: status::Status, body: &T) -> IronResult<Response> {
+    let content_type: Mime = "application/json".parse().expect("json content type");
+    let body = serde_json::to_string(body).expect("serializable response");
+    Ok(Response::with((status, content_type, body)))
+}
+
+fn not_found(message: &str) -> IronResult<Response> {
+    json(status::NotFound, &json!({ "error": message }))
+}
+
+// GET /api/rooms, /api/rooms/{id} and /api/rooms/{id}/state
+struct RoomsHandler;
+
+impl Handler for RoomsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<&str> = req.url.path().into_iter().filter(|p| !p.is_empty()).collect();
+        let id = match path.first() {
+            None => return json(status::Ok, &ROOMS.list()),
+            Some(id) => match id.parse::<RoomId>() {
+                Ok(id) => id,
+                Err(_) => return not_found("No such room"),
+            },
+        };
+        match &path[1..] {
+            [] => match ROOMS.summary(id) {
+                Some(summary) => json(status::Ok, &summary),
+                None => not_found("No such room"),
+            },
+            ["state"] => match ROOMS.state(id) {
+                Some(Some(state)) => json(status::Ok, &*state),
+                Some(None) => json(status::Forbidden, &json!({ "error": "The room's state isn't public" })),
+                None => not_found("No such room"),
+            },
+            _ => not_found("No such endpoint"),
+        }
+    }
+}
+
+// GET /api/results, newest first, and /api/results/leaderboard
+struct ResultsHandler {
+    results: ResultsStore,
+}
+
+impl Handler for ResultsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<&str> = req.url.path().into_iter().filter(|p| !p.is_empty()).collect();
+        let mut recorded = match self.results.load() {
+            Ok(recorded) => recorded,
+            Err(e) => return json(status::InternalServerError, &json!({ "error": e })),
+        };
+        match &path[..] {
+            [] => {
+                recorded.reverse();
+                json(status::Ok, &recorded)
+            }
+            ["leaderboard"] => json(status::Ok, &results::leaderboard(&recorded)),
+            _ => not_found("No such endpoint"),
+        }
+    }
+}
@@ -116,12 +116,17 @@ let glShapes = (function() {
 })();
 
 let metro = (function() {
+  // Must match PROTOCOL_VERSION in src/protocol.rs
+  const PROTOCOL_VERSION = 2;
+  // JSON unless the page was opened with ?encoding=msgpack
+  const ENCODING = new URLSearchParams(window.location.search).get('encoding') === 'msgpack' ? 'MessagePack' : 'Json';
   let game_started = false;
   let game_model = {
     lobby_count: 0,
   };
   let this_player = null;
   let touched_station = null;
+  let bot_kind = null;
 
   let displayElements = {};
   function hideElement(el) { el.style.display = 'none'; }
@@ -132,8 +137,22 @@ let metro = (function() {
   function getProjectionMatrix() {
     let canvasWidth = displayElements.canvas.width;
     let canvasHeight = displayElements.canvas.height;
-    return makeOrtho(-(canvasWidth / 2), (canvasWidth / 2)
-      , (canvasHeight / 2), -(canvasHeight / 2)
+    if (typeof game_model.state === 'undefined') {
+      return makeOrtho(-(canvasWidth / 2), (canvasWidth / 2)
+        , (canvasHeight / 2), -(canvasHeight / 2)
+        , -1, 1);
+    }
+    // Zoom out as the map grows so the whole playable area stays on screen
+    let state = game_model.state;
+    let mapWidth = state.max_x - state.min_x;
+    let mapHeight = state.max_y - state.min_y;
+    let scale = Math.min(canvasWidth / mapWidth, canvasHeight / mapHeight);
+    let halfWidth = canvasWidth / scale / 2;
+    let halfHeight = canvasHeight / scale / 2;
+    let centreX = (state.min_x + state.max_x) / 2;
+    let centreY = (state.min_y + state.max_y) / 2;
+    return makeOrtho(centreX - halfWidth, centreX + halfWidth
+      , centreY + halfHeight, centreY - halfHeight
       , -1, 1);
   }
 
@@ -207,7 +226,7 @@ let metro = (function() {
           let passengersY = station_pos[1] - (station_size / 2) + (passengerSize / 2);
           let passengerY = passengersY + Math.floor(p / 5) * (passengerSize + passengerMargin);
           let passengerPos = [ passengerX, passengerY];
-          let shape = stationShape(passenger);
+          let shape = stationShape(passenger.destination);
           if (shape !== null) {
             glShapes.drawShape(gl, program, shape, passengerPos, [0, 0, 0], passengerSize, passengerSize, 0);
           }
@@ -275,7 +294,7 @@ let metro = (function() {
           train.position[0] + passengerOffset.e(1),
           train.position[1] + passengerOffset.e(2),
         ];
-        let shape = stationShape(passenger);
+        let shape = stationShape(passenger.destination);
         if (shape !== null) {
           glShapes.drawShape(gl, program, shape, passengerPos, [trainColour[0] + 0.1, trainColour[1] + 0.1, trainColour[2] + 0.1], passengerSize, passengerSize, travelAngle);
         }
@@ -284,12 +303,29 @@ let metro = (function() {
     }
   }
 
+  function draw_terrain() {
+    let waterColour = [0.75, 0.86, 0.93];
+    let terrain = game_model.state.terrain || [];
+    for (let i = 0; i < terrain.length; i++) {
+      let area = terrain[i];
+      if (area.Lake) {
+        let diameter = area.Lake.radius * 2;
+        glShapes.drawShape(gl, program, glShapes.circle(gl), area.Lake.centre, waterColour, diameter, diameter, 0);
+      }
+      if (area.River) {
+        let river = area.River;
+        glShapes.drawLine(gl, program, river.from[0], river.from[1], river.to[0], river.to[1], river.width, waterColour);
+      }
+    }
+  }
+
   function draw_state() {
     gl.clearColor(0.945, 0.941, 0.922, 1.0);
     gl.clear(gl.COLOR_BUFFER_BIT);
     program.use();
     let ortho = getProjectionMatrix();
     program.setUniformMat4('projection', ortho);
+    draw_terrain();
     draw_lines();
     draw_trains();
     draw_stations();
@@ -302,7 +338,7 @@ let metro = (function() {
     let player_line_count = 0;
     for (let i = 0; i < game_model.state.lines.length; i++) {
       let line = game_model.state.lines[i];
-      if (line.owning_player != this_player) {
+      if (!isOurs(line.owning_player)) {
         continue;
       }
 
@@ -316,10 +352,38 @@ let metro = (function() {
     }
   }
 
+  function teamOf(player) {
+    return (game_model.state.teams || {})[player];
+  }
+
+  // Teammates build on each other's lines, and some rulesets share them all
+  function isOurs(owner) {
+    if (game_model.state.shared_network) { return true; }
+    let team = teamOf(this_player);
+    return owner == this_player || (typeof team !== 'undefined' && teamOf(owner) === team);
+  }
+
   function set_player_score() {
     if (typeof game_model.state.scores !== 'undefined') {
       let playerScore = game_model.state.scores[this_player] || 0;
-      displayElements.score.innerHTML = 'Score: ' + playerScore;
+      let scoreText = 'Score: ' + Math.round(playerScore * 10) / 10;
+      if (game_model.state.shared_network) {
+        let delivered = 0;
+        for (let player in game_model.state.delivery_stats) {
+          delivered += game_model.state.delivery_stats[player].delivered;
+        }
+        scoreText = 'Delivered together: ' + delivered;
+      }
+      let team = teamOf(this_player);
+      if (typeof team !== 'undefined' && !game_model.state.shared_network) {
+        let teamScore = (game_model.state.team_scores || {})[team] || 0;
+        scoreText += ' (team ' + (team + 1) + ': ' + Math.round(teamScore * 10) / 10 + ')';
+      }
+      let roundTrip = (game_model.state.round_trips || {})[this_player];
+      if (typeof roundTrip !== 'undefined') {
+        scoreText += ' (' + roundTrip + 'ms)';
+      }
+      displayElements.score.innerHTML = scoreText;
       let canvasRect = displayElements.canvas.getBoundingClientRect();
       let canvasWidth = canvasRect.width;
       let canvasLeft = canvasRect.left;
@@ -375,6 +439,63 @@ let metro = (function() {
     ws.send(JSON.stringify(obj));
   }
 
+  function fillSelect(select, options, selected, enabled) {
+    while (select.firstChild) {
+      select.removeChild(select.firstChild);
+    }
+    for (let i = 0; i < options.length; i++) {
+      let option = document.createElement('option');
+      option.value = options[i];
+      option.text = options[i];
+      option.selected = options[i] === selected;
+      select.appendChild(option);
+    }
+    select.disabled = !enabled;
+  }
+
+  function showLobbySettings(settings) {
+    let isHost = settings.host === this_player;
+    fillSelect(displayElements.map, settings.maps, settings.map, isHost);
+    fillSelect(displayElements.scoring, settings.scoring_policies, settings.scoring, isHost);
+    fillSelect(displayElements.visibility, settings.visibilities, settings.visibility, isHost);
+    fillSelect(displayElements.ruleset, settings.rulesets, settings.ruleset, isHost);
+    displayElements.bot.disabled = !isHost || settings.bot_kinds.length === 0;
+    bot_kind = settings.bot_kinds[0];
+    let teamCounts = ['Off', '2', '3', '4'];
+    fillSelect(displayElements.teamCount, teamCounts, settings.team_count ? String(settings.team_count) : 'Off', isHost);
+    let teamNames = [];
+    for (let i = 0; i < settings.team_count; i++) { teamNames.push('Team ' + (i + 1)); }
+    let team = settings.teams[this_player];
+    fillSelect(displayElements.team, teamNames, typeof team === 'undefined' ? '' : 'Team ' + (team + 1), teamNames.length > 0);
+  }
+
+  function showGameOver(result) {
+    let scores = result.players.map(function(p) {
+      return p.name + ' ' + p.score.toFixed(1);
+    });
+    displayElements.status.innerText = 'Game over (' + describeGameEnd(result.ended) + ' in week ' +
+      (result.weeks + 1) + '): ' + scores.join(', ');
+    game_started = false;
+    hideElement(displayElements.game);
+    showElement(displayElements.lobby);
+  }
+
+  function showChat(chat) {
+    let log = displayElements.chat.querySelector('#chat-log');
+    let line = document.createElement('div');
+    let from = chat.from === this_player ? 'You' : 'Player ' + chat.from;
+    line.textContent = from + ': ' + chat.text;
+    log.appendChild(line);
+    log.scrollTop = log.scrollHeight;
+  }
+
+  function describeGameEnd(ended) {
+    if (typeof ended.Overcrowded !== 'undefined') {
+      return 'station ' + ended.Overcrowded + ' overcrowded';
+    }
+    return 'everyone left';
+  }
+
   function handleWebSocketMessage(message) {
     if (message.LobbyCount) {
       game_model.lobby_count = message.LobbyCount;
@@ -387,6 +508,25 @@ let metro = (function() {
         game_started = true;
       }
     }
+    if (message.LobbySettings) {
+      showLobbySettings(message.LobbySettings);
+    }
+    if (message.Welcome) {
+      displayElements.status.innerText = 'Connected';
+    }
+    if (message.Reject) {
+      displayElements.status.innerText = 'Rejected: ' + message.Reject.reason;
+    }
+    if (message.Chat) {
+      showChat(message.Chat);
+    }
+    if (message.GameOver) {
+      showGameOver(message.GameOver);
+    }
+    if (message.Warning) {
+      console.warn(message.Warning);
+      displayElements.status.innerText = message.Warning;
+    }
     if (typeof message.You !== 'undefined') {
       alert(message.You);
       this_player = message.You;
@@ -395,12 +535,18 @@ let metro = (function() {
 
   function setupWebSocket(address) {
     ws = new WebSocket(address);
+    ws.binaryType = 'arraybuffer';
     displayElements.status.innerText = 'Connecting';
     ws.onopen = function() {
-      displayElements.status.innerText = 'Connected';
+      displayElements.status.innerText = 'Saying hello';
+      sendWebSocketMessage({ Hello: { protocol_version: PROTOCOL_VERSION, client_name: 'metro.js', encoding: ENCODING } });
     };
     ws.onmessage = function(m) {
-      handleWebSocketMessage(JSON.parse(m.data));
+      if (typeof m.data === 'string') {
+        handleWebSocketMessage(JSON.parse(m.data));
+      } else {
+        handleWebSocketMessage(msgpack.decode(new Uint8Array(m.data)));
+      }
     };
     ws.onerror = function(m) {
       alert(JSON.stringify(m));
@@ -420,7 +566,38 @@ let metro = (function() {
     sendWebSocketMessage({ InsertAtLineEnd: [ lineId, stationId ] });
   }
   function sendStartGame() {
-    sendWebSocketMessage({ StartGame: null });
+    sendWebSocketMessage('StartGame');
+  }
+  function sendSelectMap(name) {
+    sendWebSocketMessage({ SelectMap: name });
+  }
+  function sendSelectScoring(name) {
+    sendWebSocketMessage({ SelectScoring: name });
+  }
+  function sendSelectVisibility(name) {
+    sendWebSocketMessage({ SelectVisibility: name });
+  }
+  function sendChat(text) {
+    sendWebSocketMessage({ Chat: text });
+  }
+  function sendEmote(emote) {
+    sendWebSocketMessage({ Emote: emote });
+  }
+  function sendSelectRuleset(name) {
+    sendWebSocketMessage({ SelectRuleset: name });
+  }
+  function sendSetTeamCount(value) {
+    sendWebSocketMessage({ SetTeamCount: value === 'Off' ? 0 : parseInt(value, 10) });
+  }
+  function sendJoinTeam(value) {
+    sendWebSocketMessage({ JoinTeam: parseInt(value.replace('Team ', ''), 10) - 1 });
+  }
+  function sendSetName(name) {
+    sendWebSocketMessage({ SetName: name });
+  }
+  function sendAddBot(kind) {
+    if (!kind) { return; }
+    sendWebSocketMessage({ AddBot: kind });
   }
 
   function handleStationDown(stationId) {
@@ -434,7 +611,7 @@ let metro = (function() {
     if (stationId !== localCopy) {
       for (let i = 0; i < game_model.state.lines.length; i++) {
         let line = game_model.state.lines[i];
-        if (line.owning_player != this_player) { continue; }
+        if (!isOurs(line.owning_player)) { continue; }
         if (line.edges.length === 0) { continue; }
         if (line.edges[0].origin == localCopy) {
           sendInsertStation(i, stationId);
@@ -447,7 +624,7 @@ let metro = (function() {
       }
       for (let i = 0; i < game_model.state.lines.length; i++) {
         let line = game_model.state.lines[i];
-        if (line.owning_player != this_player) { continue; }
+        if (!isOurs(line.owning_player)) { continue; }
         if (line.edges.length !== 0) { continue; }
         sendNewLine(localCopy, stationId);
         return;
@@ -485,8 +662,17 @@ let metro = (function() {
     }
   }
 
+  function isLobbyControl(el) {
+    return el === displayElements.map || el === displayElements.scoring ||
+      el === displayElements.visibility || el === displayElements.ruleset || el === displayElements.bot ||
+      el === displayElements.name || el === displayElements.teamCount ||
+      el === displayElements.team || el.tagName === 'A' ||
+      displayElements.chat.contains(el);
+  }
+
   function attachInputs() {
     window.addEventListener('touchstart', function(e) {
+      if (isLobbyControl(e.target)) { return; }
       let touchPoint = e.touches[0];
       let bounding = displayElements.canvas.getBoundingClientRect();
       let x = touchPoint.pageX - bounding.x;
@@ -501,6 +687,7 @@ let metro = (function() {
       handlePointerUp(x, y, displayElements.canvas.width);
     });
     window.addEventListener('mousedown', function(e) {
+      if (isLobbyControl(e.target)) { return; }
       let bounding = displayElements.canvas.getBoundingClientRect();
       let x = e.clientX - bounding.x;
       let y = e.clientY - bounding.y;
@@ -514,7 +701,7 @@ let metro = (function() {
     });
   }
 
-  function setup(websocketAddress, gameEl, lobbyEl, statusEl, countEl, canvasEl, scoreEl) {
+  function setup(websocketAddress, gameEl, lobbyEl, statusEl, countEl, canvasEl, scoreEl, mapEl, scoringEl, visibilityEl, rulesetEl, botEl, nameEl, chatEl, teamCountEl, teamEl) {
     hideElement(gameEl);
     showElement(lobbyEl);
     displayElements.status = statusEl;
@@ -523,6 +710,51 @@ let metro = (function() {
     displayElements.count = countEl;
     displayElements.game = gameEl;
     displayElements.score = scoreEl;
+    displayElements.map = mapEl;
+    mapEl.addEventListener('change', function() {
+      sendSelectMap(mapEl.value);
+    });
+    displayElements.scoring = scoringEl;
+    scoringEl.addEventListener('change', function() {
+      sendSelectScoring(scoringEl.value);
+    });
+    displayElements.visibility = visibilityEl;
+    visibilityEl.addEventListener('change', function() {
+      sendSelectVisibility(visibilityEl.value);
+    });
+    displayElements.ruleset = rulesetEl;
+    rulesetEl.addEventListener('change', function() {
+      sendSelectRuleset(rulesetEl.value);
+    });
+    displayElements.bot = botEl;
+    botEl.addEventListener('click', function() {
+      sendAddBot(bot_kind);
+    });
+    displayElements.name = nameEl;
+    nameEl.addEventListener('change', function() {
+      sendSetName(nameEl.value);
+    });
+    displayElements.teamCount = teamCountEl;
+    teamCountEl.addEventListener('change', function() {
+      sendSetTeamCount(teamCountEl.value);
+    });
+    displayElements.team = teamEl;
+    teamEl.addEventListener('change', function() {
+      sendJoinTeam(teamEl.value);
+    });
+    displayElements.chat = chatEl;
+    let chatInput = chatEl.querySelector('#chat-input');
+    chatInput.addEventListener('keydown', function(e) {
+      if (e.key === 'Enter' && chatInput.value.trim() !== '') {
+        sendChat(chatInput.value);
+        chatInput.value = '';
+      }
+    });
+    chatEl.querySelectorAll('[data-emote]').forEach(function(button) {
+      button.addEventListener('click', function() {
+        sendEmote(button.dataset.emote);
+      });
+    });
     canvasEl.width = document.body.clientWidth;
     canvasEl.height = document.body.clientHeight;
 
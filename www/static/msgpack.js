@@ -0,0 +1,76 @@
+// Just enough MessagePack decoding for the state updates the server sends.
+let msgpack = (function() {
+  let utf8 = new TextDecoder('utf-8');
+
+  function decode(bytes) {
+    let view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+    let offset = 0;
+
+    function str(length) {
+      let s = utf8.decode(bytes.subarray(offset, offset + length));
+      offset += length;
+      return s;
+    }
+    function bin(length) {
+      let b = bytes.slice(offset, offset + length);
+      offset += length;
+      return b;
+    }
+    function array(length) {
+      let a = [];
+      for (let i = 0; i < length; i++) {
+        a.push(value());
+      }
+      return a;
+    }
+    function map(length) {
+      let m = {};
+      for (let i = 0; i < length; i++) {
+        let key = value();
+        m[key] = value();
+      }
+      return m;
+    }
+    function value() {
+      let b = view.getUint8(offset++);
+      let v;
+      if (b <= 0x7f) { return b; }
+      if (b >= 0xe0) { return b - 0x100; }
+      if ((b & 0xf0) === 0x80) { return map(b & 0x0f); }
+      if ((b & 0xf0) === 0x90) { return array(b & 0x0f); }
+      if ((b & 0xe0) === 0xa0) { return str(b & 0x1f); }
+      switch (b) {
+        case 0xc0: return null;
+        case 0xc2: return false;
+        case 0xc3: return true;
+        case 0xc4: v = view.getUint8(offset); offset += 1; return bin(v);
+        case 0xc5: v = view.getUint16(offset); offset += 2; return bin(v);
+        case 0xc6: v = view.getUint32(offset); offset += 4; return bin(v);
+        case 0xca: v = view.getFloat32(offset); offset += 4; return v;
+        case 0xcb: v = view.getFloat64(offset); offset += 8; return v;
+        case 0xcc: v = view.getUint8(offset); offset += 1; return v;
+        case 0xcd: v = view.getUint16(offset); offset += 2; return v;
+        case 0xce: v = view.getUint32(offset); offset += 4; return v;
+        case 0xcf: v = view.getUint32(offset) * 0x100000000 + view.getUint32(offset + 4); offset += 8; return v;
+        case 0xd0: v = view.getInt8(offset); offset += 1; return v;
+        case 0xd1: v = view.getInt16(offset); offset += 2; return v;
+        case 0xd2: v = view.getInt32(offset); offset += 4; return v;
+        case 0xd3: v = view.getInt32(offset) * 0x100000000 + view.getUint32(offset + 4); offset += 8; return v;
+        case 0xd9: v = view.getUint8(offset); offset += 1; return str(v);
+        case 0xda: v = view.getUint16(offset); offset += 2; return str(v);
+        case 0xdb: v = view.getUint32(offset); offset += 4; return str(v);
+        case 0xdc: v = view.getUint16(offset); offset += 2; return array(v);
+        case 0xdd: v = view.getUint32(offset); offset += 4; return array(v);
+        case 0xde: v = view.getUint16(offset); offset += 2; return map(v);
+        case 0xdf: v = view.getUint32(offset); offset += 4; return map(v);
+      }
+      throw new Error('Unsupported MessagePack byte 0x' + b.toString(16));
+    }
+
+    return value();
+  }
+
+  return {
+    decode: decode,
+  };
+})();